@@ -0,0 +1,55 @@
+use std::{error, ffi::OsStr, fmt};
+use super::host::HOST;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+  Malformed(String),
+  UnknownKey(String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Malformed(cond) => {
+        write!(f, "malformed condition \"{}\", expected KEY.VALUE", cond)
+      }
+      Error::UnknownKey(key) => write!(f, "unknown condition key \"{}\"", key),
+    }
+  }
+}
+
+impl error::Error for Error {}
+
+/// Name of a file without the conditions of an alternate.
+pub fn base(name: &OsStr) -> &OsStr {
+  match name.to_str().and_then(split) {
+    None => name,
+    Some((base, _)) => OsStr::new(base),
+  }
+}
+
+/// Whether every one of the comma separated conditions holds on the current
+/// machine.
+pub fn matches(conds: &str) -> Result<bool, Error> {
+  for cond in conds.split(',') {
+    let (key, val) = cond.split_once('.')
+      .ok_or_else(|| Error::Malformed(cond.to_owned()))?;
+    let fact = match key {
+      "arch" => HOST.arch,
+      "host" | "hostname" => &HOST.hostname,
+      "os" => HOST.os,
+      "user" => &HOST.user,
+      _ => return Err(Error::UnknownKey(key.to_owned())),
+    };
+    if fact != val {
+      return Ok(false);
+    }
+  }
+  Ok(true)
+}
+
+/// Splits the name of an alternate, such as `config##os.linux`, into its base
+/// name and its conditions.
+pub fn split(name: &str) -> Option<(&str, &str)> {
+  name.split_once("##").filter(|(base, _)| !base.is_empty())
+}
@@ -0,0 +1,58 @@
+use serde::Deserialize;
+use std::{env::current_dir as cd, path::{Path, PathBuf}};
+use super::state;
+
+/// Strategy used to keep files out of the way instead of removing them.
+#[derive(
+  Clone,
+  Copy,
+  Debug,
+  Default,
+  Deserialize,
+  Eq,
+  Ord,
+  PartialEq,
+  PartialOrd,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backup {
+  Dir,
+  #[default]
+  None,
+  Suffix,
+}
+
+impl Backup {
+  /// Where `p` should be backed up to at the time `stamp`, if anywhere.
+  /// Paths already taken, such as by an earlier backup made within the same
+  /// second, are skipped by appending a counter to the stamp.
+  pub fn path(&self, p: &Path, stamp: u64) -> Option<PathBuf> {
+    let candidate = |n: usize| {
+      let stamp = match n {
+        0 => stamp.to_string(),
+        n => format!("{}-{}", stamp, n),
+      };
+      match self {
+        Backup::Dir => {
+          let p = cd().unwrap().join(p);
+          let dir = state::dir().join("backup").join(stamp);
+          Some(dir.join(p.strip_prefix("/").unwrap()))
+        }
+        Backup::None => None,
+        Backup::Suffix => {
+          let mut s = p.as_os_str().to_owned();
+          s.push(format!(".shove-{}", stamp));
+          Some(s.into())
+        }
+      }
+    };
+    (0..)
+      .map(candidate)
+      .find(|to| !matches!(to, Some(to) if taken(to)))
+      .unwrap()
+  }
+}
+
+fn taken(p: &Path) -> bool {
+  p.symlink_metadata().is_ok()
+}
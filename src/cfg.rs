@@ -1,12 +1,89 @@
-use serde::Deserialize;
-use super::dot::Dots;
+use glob::{glob, Pattern};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+  collections::BTreeMap,
+  env::{current_dir as cd, var_os},
+  fs::read_to_string,
+  io,
+  mem::take,
+  ops::RangeBounds,
+  path::{Path, PathBuf},
+};
+use strsim::jaro_winkler;
+use super::{
+  backup::Backup,
+  dot::{opt_regexes, regexes, Dots},
+  mode::Mode,
+};
 
+/// Name of the config file looked up in the current dir and its parents.
+pub const FILE: &str = ".shove.toml";
+
+/// Definitions kept in a file apart from the config file, merged into it.
 #[derive(Clone, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
-#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Fragment {
+  #[serde(default)]
+  pub dots: Dots,
+
+  #[serde(default)]
+  pub groups: BTreeMap<String, Vec<String>>,
+
+  #[serde(default)]
+  pub profiles: BTreeMap<String, Profile>,
+
+  #[serde(default)]
+  pub vars: BTreeMap<String, String>,
+}
+
+/// Dots and settings chosen together, overriding the global ones.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+  #[serde(default)]
+  pub absolute: Option<bool>,
+
+  #[serde(default)]
+  pub backup: Option<Backup>,
+
+  #[serde(default)]
+  pub berserker: Option<bool>,
+
+  #[serde(default)]
+  pub depth: Option<usize>,
+
+  /// Dots selected by the profile, which may be groups or tags prefixed with
+  /// "@", like on the command line.
+  #[serde(default)]
+  pub dots: Vec<String>,
+
+  #[serde(default)]
+  pub follow: Option<bool>,
+
+  #[serde(default, deserialize_with = "opt_regexes")]
+  pub ignore: Option<Vec<String>>,
+
+  #[serde(default)]
+  pub mode: Option<Mode>,
+
+  #[serde(default)]
+  pub rage: Option<usize>,
+
+  #[serde(default)]
+  pub template: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub struct Cfg {
   #[serde(default)]
   pub absolute: bool,
 
+  #[serde(default)]
+  pub backup: Backup,
+
   #[serde(default)]
   pub berserker: bool,
 
@@ -20,8 +97,269 @@ pub struct Cfg {
   pub follow: bool,
 
   #[serde(default)]
+  pub groups: BTreeMap<String, Vec<String>>,
+
+  #[serde(default, deserialize_with = "regexes")]
   pub ignore: Vec<String>,
 
+  /// Patterns of fragment files, relative to the dir holding the config file.
+  #[serde(default)]
+  pub include: Vec<String>,
+
+  #[serde(default)]
+  pub mode: Mode,
+
+  #[serde(default)]
+  pub modes: BTreeMap<String, Mode>,
+
+  /// Absolute path of the config file, which is empty if there's none.
+  #[serde(skip)]
+  pub path: PathBuf,
+
+  #[serde(default)]
+  pub profiles: BTreeMap<String, Profile>,
+
   #[serde(default)]
   pub rage: usize,
+
+  #[serde(default)]
+  pub template: Option<String>,
+
+  #[serde(default)]
+  pub vars: BTreeMap<String, String>,
+}
+
+impl Cfg {
+  pub fn load(path: &Path) -> Result<Self, String> {
+    let mut cfg: Self = parse(path)?;
+    cfg.path = path.canonicalize().map_err(|err| {
+      format!("unable to resolve \"{}\": {}", path.display(), err)
+    })?;
+
+    // Files where each dot, group, profile and var was defined.
+    let mut origins = BTreeMap::new();
+    for name in cfg.dots.keys() {
+      origins.insert(("dot", name.clone()), path.to_owned());
+    }
+    for name in cfg.groups.keys() {
+      origins.insert(("group", name.clone()), path.to_owned());
+    }
+    for name in cfg.profiles.keys() {
+      origins.insert(("profile", name.clone()), path.to_owned());
+    }
+    for name in cfg.vars.keys() {
+      origins.insert(("var", name.clone()), path.to_owned());
+    }
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let dir = Pattern::escape(&dir.to_string_lossy());
+    for pattern in &cfg.include {
+      let full = Path::new(&dir).join(pattern);
+      let paths = glob(&full.to_string_lossy()).map_err(|err| {
+        format!("invalid include \"{}\": {}", pattern, err.msg)
+      })?;
+
+      for frag_path in paths {
+        let frag_path = frag_path.map_err(|err| {
+          format!("unable to include \"{}\": {}", err.path().display(), err)
+        })?;
+        let mut frag: Fragment = parse(&frag_path)?;
+
+        let mut merge = |kind, names: Vec<String>| {
+          for name in names {
+            let key = (kind, name);
+            if let Some(other) = origins.get(&key) {
+              return Err(format!(
+                "{} \"{}\" is defined in both \"{}\" and \"{}\"",
+                kind,
+                key.1,
+                other.display(),
+                frag_path.display(),
+              ));
+            }
+            origins.insert(key, frag_path.clone());
+          }
+          Ok(())
+        };
+        merge("dot", frag.dots.keys().cloned().collect())?;
+        merge("group", frag.groups.keys().cloned().collect())?;
+        merge("profile", frag.profiles.keys().cloned().collect())?;
+        merge("var", frag.vars.keys().cloned().collect())?;
+
+        cfg.dots.append(&mut frag.dots);
+        cfg.groups.append(&mut frag.groups);
+        cfg.profiles.append(&mut frag.profiles);
+        cfg.vars.append(&mut frag.vars);
+      }
+    }
+
+    Ok(cfg)
+  }
+
+  /// Overrides settings with the ones from the profile `name`, returning the
+  /// dots it selects, or `None` if there's no such profile.
+  pub fn profile(&mut self, name: &str) -> Option<Vec<String>> {
+    let p = self.profiles.remove(name)?;
+    self.absolute = p.absolute.unwrap_or(self.absolute);
+    self.backup = p.backup.unwrap_or(self.backup);
+    self.berserker = p.berserker.unwrap_or(self.berserker);
+    self.depth = p.depth.unwrap_or(self.depth);
+    self.follow = p.follow.unwrap_or(self.follow);
+    self.ignore = p.ignore.unwrap_or(take(&mut self.ignore));
+    self.mode = p.mode.unwrap_or(self.mode);
+    self.rage = p.rage.unwrap_or(self.rage);
+    self.template = p.template.or(take(&mut self.template));
+    Some(p.dots)
+  }
+}
+
+/// Reads and parses the TOML file at `path`.
+fn parse<T>(path: &Path) -> Result<T, String> where T: DeserializeOwned {
+  let s = read_to_string(path)
+    .map_err(|err| format!("unable to read \"{}\": {}", path.display(), err))?;
+  toml::from_str(&s).map_err(|err| diagnose(path, &s, &err))
+}
+
+/// Describes a parse error of the TOML file at `path` whose contents are `s`,
+/// pointing where it happened.
+fn diagnose(path: &Path, s: &str, err: &toml::de::Error) -> String {
+  lazy_static! {
+    static ref BAD_REGEX: Regex = Regex::new(r#"^invalid regex "(.*)": "#).unwrap();
+    static ref FOR_KEY: Regex =
+      Regex::new(r"for key `(?:.*\.)?([^.`]*)`$").unwrap();
+  }
+
+  let mut msg = err.to_string();
+  let (line, col) = match err.line_col() {
+    None => return format!("in \"{}\": {}", path.display(), suggest(&msg).0),
+    Some(line_col) => line_col,
+  };
+
+  let at = format!(" at line {} column {}", line + 1, col + 1);
+  if msg.ends_with(&at) {
+    msg.truncate(msg.len() - at.len());
+  }
+
+  // Errors raised for a whole table point at its start, so look for what's
+  // wrong close to there.
+  let (msg, key) = suggest(&msg);
+  let assign = |key: &str| {
+    let key = format!(r"(?:^|[\s{{,.])({})\s*=", regex::escape(key));
+    Regex::new(&key).unwrap()
+  };
+  let found = match (key, BAD_REGEX.captures(&msg)) {
+    (Some(key), _) => locate(s, &assign(&key), line, ..),
+    (None, Some(caps)) => {
+      let re = format!(r#"['"]({})['"]"#, regex::escape(&caps[1]));
+      locate(s, &Regex::new(&re).unwrap(), line, ..)
+    }
+    // Other errors about a key of the table are looked for within it.
+    (None, None) => match FOR_KEY.captures(&msg) {
+      Some(caps) if header(s, line) => {
+        let end = (line + 1..s.lines().count())
+          .find(|i| header(s, *i))
+          .unwrap_or(usize::MAX);
+        locate(s, &assign(&caps[1]), line, line..end)
+      }
+      _ => None,
+    },
+  };
+  let (line, col) = found.unwrap_or((line, col));
+
+  let text = s.lines().nth(line).unwrap_or_default();
+  let num = (line + 1).to_string();
+  let pad = " ".repeat(num.len());
+  format!(
+    "in \"{}\", line {}, column {}: {}\n{} |\n{} | {}\n{} | {}^",
+    path.display(),
+    line + 1,
+    col + 1,
+    msg,
+    pad,
+    num,
+    text,
+    pad,
+    " ".repeat(text[..col].chars().count()),
+  )
+}
+
+/// Whether the line `line` of `s` is a table header.
+fn header(s: &str, line: usize) -> bool {
+  s.lines().nth(line).is_some_and(|text| text.trim_start().starts_with('['))
+}
+
+/// Finds the match of `re` in `s` closest to `line` among the lines `within`,
+/// returning its line and the byte column of its first group.
+fn locate<R>(s: &str, re: &Regex, line: usize, within: R)
+  -> Option<(usize, usize)>
+  where R: RangeBounds<usize>
+{
+  s.lines()
+    .enumerate()
+    .filter(|(i, _)| within.contains(i))
+    .filter_map(|(i, text)| re.captures(text).map(|caps| (i, caps)))
+    .min_by_key(|(i, _)| i.abs_diff(line))
+    .map(|(i, caps)| (i, caps.get(1).unwrap().start()))
+}
+
+/// Rewords an error about an unknown key, suggesting the closest known key,
+/// and returning the unknown key as well.
+fn suggest(msg: &str) -> (String, Option<String>) {
+  lazy_static! {
+    static ref UNKNOWN: Regex = Regex::new(
+      r"^unknown field `([^`]*)`, (?:expected (.*?)|there are no fields)( for key `.*`)?$",
+    ).unwrap();
+    static ref NAME: Regex = Regex::new(r"`([^`]*)`").unwrap();
+  }
+
+  let caps = match UNKNOWN.captures(msg) {
+    None => return (msg.to_owned(), None),
+    Some(caps) => caps,
+  };
+  let key = &caps[1];
+  let within = caps.get(3)
+    .map(|m| m.as_str().replacen(" for key", " in", 1))
+    .unwrap_or_default();
+
+  let known = caps.get(2).map_or("", |m| m.as_str());
+  let closest = NAME.captures_iter(known)
+    .map(|name| name.get(1).unwrap().as_str())
+    .map(|name| (jaro_winkler(key, name), name))
+    .filter(|(score, _)| *score > 0.8)
+    .max_by(|a, b| a.0.total_cmp(&b.0));
+
+  let msg = match closest {
+    None => format!("unknown key `{}`{}", key, within),
+    Some((_, name)) => {
+      format!("unknown key `{}`{}, did you mean `{}`?", key, within, name)
+    }
+  };
+  (msg, Some(key.to_owned()))
+}
+
+/// Finds the config file, which is `path` if given, or else the `.shove.toml`
+/// closest to the current dir among its ancestors, or else the user config
+/// file.
+pub fn find(path: Option<&Path>) -> io::Result<Option<PathBuf>> {
+  if let Some(path) = path {
+    return Ok(Some(path.to_owned()));
+  }
+
+  for dir in cd()?.ancestors() {
+    let path = dir.join(FILE);
+    if path.is_file() {
+      return Ok(Some(path));
+    }
+  }
+
+  Ok(Some(user()).filter(|path| path.is_file()))
+}
+
+/// Config file used when there's no `.shove.toml` around.
+pub fn user() -> PathBuf {
+  let base = match var_os("XDG_CONFIG_HOME") {
+    Some(p) if !p.is_empty() => PathBuf::from(p),
+    _ => PathBuf::from(shellexpand::tilde("~/.config").as_ref()),
+  };
+  base.join("shove").join("config.toml")
 }
@@ -1,5 +1,5 @@
-use clap::{ArgEnum, Parser};
-use std::str::FromStr;
+use clap::{ArgEnum, Parser, Subcommand};
+use std::{path::PathBuf, str::FromStr};
 
 #[derive(ArgEnum, Clone, Copy, Debug)]
 pub enum ColorWhen {
@@ -8,51 +8,165 @@ pub enum ColorWhen {
   Never,
 }
 
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum Tool {
+  Chezmoi,
+  Stow,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Cmd {
+  /// Report every problem with the config without changing anything.
+  Check,
+
+  /// Write a config file with the dots of a tree managed by GNU Stow or
+  /// chezmoi.
+  Import {
+    /// Rename "dot-" prefixes of Stow packages into ".", like Stow does.
+    #[clap(long)]
+    dotfiles: bool,
+
+    /// Overwrite the config file if it already exists.
+    #[clap(long)]
+    force: bool,
+
+    /// Stow dir or chezmoi source dir.
+    #[clap(index = 2, parse(from_os_str), value_name = "DIR")]
+    dir: PathBuf,
+
+    /// Dir which dots are shoved into, instead of Stow's or chezmoi's default.
+    #[clap(long, parse(from_os_str), value_name = "DIR")]
+    target: Option<PathBuf>,
+
+    /// Tool managing the tree.
+    #[clap(arg_enum, index = 1, value_name = "TOOL")]
+    tool: Tool,
+  },
+
+  /// Write a config file with a dot for each dir in the current dir.
+  Init {
+    /// Overwrite the config file if it already exists.
+    #[clap(long)]
+    force: bool,
+
+    /// Confirm the dest path of each dot.
+    #[clap(long, short)]
+    interactive: bool,
+  },
+
+  /// Put backed up files back in place.
+  Restore {
+    /// Dots to be restored.
+    #[clap(value_name = "DOT")]
+    dots: Vec<String>,
+  },
+
+  /// Show the state of dotfiles without changing anything.
+  Status {
+    /// Dots to be checked.
+    #[clap(value_name = "DOT")]
+    dots: Vec<String>,
+  },
+
+  /// Revert the changes made by the last run.
+  Undo,
+}
+
 /// Stow, but angry.
 #[derive(Debug, Parser)]
 pub struct Opts {
+  /// Move conflicting files into the dots before shoving.
+  #[clap(conflicts_with_all = &["prune", "unshove"], long)]
+  pub adopt: bool,
+
   /// Shove with absolute path.
-  #[clap(long, short = '/', value_name = "SWITCH")]
+  #[clap(global = true, long, short = '/', value_name = "SWITCH")]
   pub absolute: Option<Switch>,
 
   /// Don't die on error.
-  #[clap(long, short, value_name = "SWITCH")]
+  #[clap(global = true, long, short, value_name = "SWITCH")]
   pub berserker: Option<Switch>,
 
+  #[clap(subcommand)]
+  pub cmd: Option<Cmd>,
+
+  /// Config file to use instead of looking for one.
+  #[clap(
+    env = "SHOVE_CONFIG",
+    global = true,
+    long,
+    parse(from_os_str),
+    value_name = "PATH",
+  )]
+  pub config: Option<PathBuf>,
+
   /// When to use colorful output.
-  #[clap(arg_enum, default_value = "auto", long, short, value_name = "WHEN")]
+  #[clap(
+    arg_enum,
+    default_value = "auto",
+    global = true,
+    long,
+    short,
+    value_name = "WHEN",
+  )]
   pub color: ColorWhen,
 
   /// Limit depth level to shove.
-  #[clap(long, short, value_name = "LEVEL")]
+  #[clap(global = true, long, short, value_name = "LEVEL")]
   pub depth: Option<usize>,
 
-  /// Dots to be shoved.
+  /// Dots to be shoved, or groups and tags prefixed with "@".
   #[clap(value_name = "DOT")]
   pub dots: Vec<String>,
 
   /// Follow links.
-  #[clap(long, short, value_name = "SWITCH")]
+  #[clap(global = true, long, short, value_name = "SWITCH")]
   pub follow: Option<Switch>,
 
   /// Do not make any change to the filesystem.
-  #[clap(long, short)]
+  #[clap(global = true, long, short)]
   pub no: bool,
 
+  /// Remove dotfiles whose source no longer exists.
+  #[clap(conflicts_with = "unshove", long, short)]
+  pub prune: bool,
+
   /// Decrease verbosity.
-  #[clap(long, parse(from_occurrences), short)]
+  #[clap(global = true, long, parse(from_occurrences), short)]
   quiet: usize,
 
+  /// Profile to choose dots and settings from.
+  #[clap(env = "SHOVE_PROFILE", global = true, long, value_name = "NAME")]
+  pub profile: Option<String>,
+
   /// Rage to remove files.
-  #[clap(long, short, value_name = "LEVEL")]
+  #[clap(global = true, long, short, value_name = "LEVEL")]
   pub rage: Option<usize>,
 
+  /// Skip dots in a group or tagged.
+  #[clap(
+    global = true,
+    long = "skip-tag",
+    number_of_values = 1,
+    value_name = "TAG",
+  )]
+  pub skip_tags: Vec<String>,
+
+  /// Shove dots in a group or tagged.
+  #[clap(
+    global = true,
+    long = "tag",
+    number_of_values = 1,
+    value_name = "TAG",
+  )]
+  pub tags: Vec<String>,
+
   /// Unshove dots.
   #[clap(long, short)]
   pub unshove: bool,
 
   /// Increase verbosity.
-  #[clap(long, parse(from_occurrences), short)]
+  #[clap(global = true, long, parse(from_occurrences), short)]
   verbose: usize,
 }
 
@@ -1,61 +1,330 @@
-use serde::Deserialize;
+use regex::Regex;
+use serde::{
+  de::{
+    self,
+    value::{MapAccessDeserializer, SeqAccessDeserializer},
+    MapAccess,
+    SeqAccess,
+    Visitor,
+  },
+  Deserialize,
+  Deserializer,
+};
 use std::{
-  collections::{btree_map::Iter as BTreeMapIter, BTreeMap},
+  collections::{BTreeMap, BTreeSet},
+  fmt,
   ops::{Deref, DerefMut},
+  slice,
+  vec,
+};
+use super::{
+  super::mode::Mode,
+  Dot,
+  ErrKind as DotErrKind,
+  Error as DotError,
+  when::When,
 };
-use super::{Dot, Error as DotError};
 
 type DotsInt = BTreeMap<String, Info>;
-type DotsIntIter<'a> = BTreeMapIter<'a, String, Info>;
 
 #[derive(Clone, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
 #[serde(from = "DotsInt")]
-pub struct Dots(DotsInt);
+pub struct Dots {
+  dots: DotsInt,
+
+  /// Names of the dots sorted by their dependencies, if already sorted.
+  order: Vec<String>,
+}
 
 impl Dots {
-  pub fn iter(&self) -> Iter {
-    Iter(self.0.iter())
+  /// Iterates over the dots, sorted by their dependencies if `sort` was
+  /// called, or else in alphabetical order.
+  pub fn iter(&self) -> Iter<'_> {
+    let sorted = self.order.iter().filter(|name| self.contains_key(*name));
+    let rest = self.keys().filter(|name| !self.order.contains(name));
+    let names: Vec<_> = sorted.chain(rest).collect();
+    Iter {dots: &self.dots, names: names.into_iter()}
+  }
+
+  /// Sorts the dots so that each one comes after the ones it requires or is
+  /// set to run after, breaking ties alphabetically.
+  pub fn sort(&mut self) -> Result<(), DotError> {
+    // Dots which each dot is still waiting for.
+    let mut deps: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (name, info) in &self.dots {
+      let mut waiting = BTreeSet::new();
+      for dep in info.requires() {
+        if !self.contains_key(dep) {
+          return Err(DotError {
+            kind: DotErrKind::MissingDep(dep.clone()),
+            name: name.clone(),
+          });
+        }
+        waiting.insert(dep.as_str());
+      }
+      let after = info.after().iter().filter(|dep| self.contains_key(*dep));
+      waiting.extend(after.map(String::as_str));
+      deps.insert(name, waiting);
+    }
+
+    let mut order = vec![];
+    while !deps.is_empty() {
+      let ready: Vec<_> = deps.iter()
+        .filter(|(_, waiting)| waiting.is_empty())
+        .map(|(name, _)| *name)
+        .collect();
+
+      if ready.is_empty() {
+        return Err(Self::cycle(&deps));
+      }
+
+      for name in ready {
+        deps.remove(name);
+        deps.values_mut().for_each(|waiting| {waiting.remove(name);});
+        order.push(name.to_owned());
+      }
+    }
+
+    self.order = order;
+    Ok(())
+  }
+
+  /// Finds a cycle among dots which are all waiting for one another.
+  fn cycle(deps: &BTreeMap<&str, BTreeSet<&str>>) -> DotError {
+    let mut path = vec![*deps.keys().next().unwrap()];
+    loop {
+      let next = *deps[path.last().unwrap()].iter().next().unwrap();
+      if let Some(i) = path.iter().position(|name| *name == next) {
+        let mut cycle: Vec<_> = path[i..].iter().map(|s| s.to_string()).collect();
+        let name = cycle.remove(0);
+        return DotError {kind: DotErrKind::DepCycle(cycle), name};
+      }
+      path.push(next);
+    }
   }
 }
 
 impl Deref for Dots {
   type Target = DotsInt;
   fn deref(&self) -> &Self::Target {
-    &self.0
+    &self.dots
   }
 }
 
 impl DerefMut for Dots {
   fn deref_mut(&mut self) -> &mut Self::Target {
-    &mut self.0
+    &mut self.dots
   }
 }
 
 impl From<DotsInt> for Dots {
-  fn from(t: DotsInt) -> Self {
-    Dots(t)
+  fn from(dots: DotsInt) -> Self {
+    Dots {dots, order: vec![]}
   }
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
-#[serde(untagged)]
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum Info {
   Str(String),
-  Table {dest: String, src: String},
+  Table(Box<Table>),
+}
+
+// Not derived as untagged, which would hide why a table failed to parse.
+impl<'de> Deserialize<'de> for Info {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: Deserializer<'de>
+  {
+    struct InfoVisitor;
+
+    impl<'de> Visitor<'de> for InfoVisitor {
+      type Value = Info;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a dest path or a table")
+      }
+
+      fn visit_map<A>(self, map: A) -> Result<Info, A::Error>
+        where A: MapAccess<'de>
+      {
+        Table::deserialize(MapAccessDeserializer::new(map))
+          .map(|t| Info::Table(Box::new(t)))
+      }
+
+      fn visit_str<E>(self, s: &str) -> Result<Info, E> where E: de::Error {
+        Ok(Info::Str(s.to_owned()))
+      }
+    }
+
+    deserializer.deserialize_any(InfoVisitor)
+  }
+}
+
+impl Info {
+  pub fn after(&self) -> &[String] {
+    match self {
+      Info::Str(_) => &[],
+      Info::Table(t) => &t.after,
+    }
+  }
+
+  pub fn requires(&self) -> &[String] {
+    match self {
+      Info::Str(_) => &[],
+      Info::Table(t) => &t.requires,
+    }
+  }
+
+  pub fn tags(&self) -> &[String] {
+    match self {
+      Info::Str(_) => &[],
+      Info::Table(t) => &t.tags,
+    }
+  }
+
+  pub fn when(&self) -> Option<&When> {
+    match self {
+      Info::Str(_) => None,
+      Info::Table(t) => t.when.as_ref(),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Table {
+  #[serde(default)]
+  pub absolute: Option<bool>,
+
+  #[serde(default)]
+  pub after: Vec<String>,
+
+  #[serde(default)]
+  pub depth: Option<usize>,
+
+  #[serde(deserialize_with = "one_or_many")]
+  pub dest: Vec<String>,
+
+  #[serde(default)]
+  pub follow: Option<bool>,
+
+  #[serde(default, deserialize_with = "opt_regexes")]
+  pub ignore: Option<Vec<String>>,
+
+  #[serde(default)]
+  pub mode: Option<Mode>,
+
+  #[serde(default)]
+  pub modes: BTreeMap<String, Mode>,
+
+  #[serde(default)]
+  pub rage: Option<usize>,
+
+  #[serde(default)]
+  pub requires: Vec<String>,
+
+  #[serde(deserialize_with = "one_or_many")]
+  pub src: Vec<String>,
+
+  #[serde(default)]
+  pub tags: Vec<String>,
+
+  #[serde(default)]
+  pub when: Option<When>,
+}
+
+/// Deserializes either a single string or a list of strings.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+  where D: Deserializer<'de>
+{
+  struct OneOrMany;
+
+  impl<'de> Visitor<'de> for OneOrMany {
+    type Value = Vec<String>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "a string or a list of strings")
+    }
+
+    fn visit_seq<A>(self, seq: A) -> Result<Vec<String>, A::Error>
+      where A: SeqAccess<'de>
+    {
+      Vec::deserialize(SeqAccessDeserializer::new(seq))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Vec<String>, E> where E: de::Error {
+      Ok(vec![s.to_owned()])
+    }
+  }
+
+  deserializer.deserialize_any(OneOrMany)
+}
+
+/// Deserializes a list of regexes, failing on the first one which doesn't
+/// compile.
+pub fn regexes<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+  where D: Deserializer<'de>
+{
+  let res = Vec::<String>::deserialize(deserializer)?;
+  for re in &res {
+    if let Err(err) = Regex::new(re) {
+      // Syntax errors span several lines, the last one telling what's wrong.
+      let err = err.to_string();
+      let why = err.lines().last().unwrap_or_default();
+      let why = why.strip_prefix("error: ").unwrap_or(why);
+      return Err(de::Error::custom(format!("invalid regex \"{}\": {}", re, why)));
+    }
+  }
+  Ok(res)
+}
+
+pub fn opt_regexes<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+  where D: Deserializer<'de>
+{
+  regexes(deserializer).map(Some)
 }
 
 #[derive(Clone, Debug)]
-pub struct Iter<'a>(DotsIntIter<'a>);
+pub struct Iter<'a> {
+  dots: &'a DotsInt,
+  names: vec::IntoIter<&'a String>,
+}
+
+impl<'a> Iter<'a> {
+  /// Builds the dot `name`, reporting every problem with it.
+  fn dot(name: &'a String, info: &'a Info) -> Result<Dot<'a>, Vec<DotError>> {
+    match info {
+      Info::Str(s) => {
+        Dot::new(name, slice::from_ref(name), slice::from_ref(s))
+      }
+      Info::Table(t) => Dot::new(name, &t.src, &t.dest).map(|dot| Dot {
+        absolute: t.absolute,
+        depth: t.depth,
+        follow: t.follow,
+        ignore: t.ignore.as_deref(),
+        mode: t.mode,
+        modes: Some(&t.modes),
+        rage: t.rage,
+        ..dot
+      }),
+    }
+  }
+
+  /// Iterates over the dots the same way, reporting every problem with each
+  /// one instead of only the first.
+  pub fn lint(self) -> impl Iterator<Item = Result<Dot<'a>, Vec<DotError>>> {
+    let dots = self.dots;
+    self.names.filter_map(move |name| {
+      let (name, info) = dots.get_key_value(name)?;
+      Some(Self::dot(name, info))
+    })
+  }
+}
 
 impl<'a> Iterator for Iter<'a> {
   type Item = Result<Dot<'a>, DotError>;
 
   fn next(&mut self) -> Option<Self::Item> {
-    let (name, info) = self.0.next()?;
-    let (src, dest) = match info {
-      Info::Str(s) => (name, s),
-      Info::Table {dest, src} => (src, dest),
-    };
-    Some(Dot::new(name, src, dest))
+    let (name, info) = self.dots.get_key_value(self.names.next()?)?;
+    Some(Self::dot(name, info).map_err(|mut errs| errs.remove(0)))
   }
 }
@@ -1,52 +1,119 @@
 mod dots;
 mod sh;
+mod when;
 
 use same_file::is_same_file;
-pub use self::{dots::Dots, sh::{Error as ShErr, Sh}};
-use std::{error, fmt, path::{PathBuf, Path}, io};
+pub use self::{
+  dots::{opt_regexes, regexes, Dots},
+  sh::{Error as ShErr, Sh},
+};
+use std::{collections::BTreeMap, error, fmt, path::{PathBuf, Path}, io};
+use super::mode::Mode;
 
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Dot<'a> {
-  pub dest: Sh<'a>,
+  pub absolute: Option<bool>,
+  pub depth: Option<usize>,
+  pub dests: Vec<Sh<'a>>,
+  pub follow: Option<bool>,
+  pub ignore: Option<&'a [String]>,
+  pub mode: Option<Mode>,
+  pub modes: Option<&'a BTreeMap<String, Mode>>,
   pub name: &'a str,
-  pub src: &'a Path,
+  pub rage: Option<usize>,
+  pub srcs: Vec<&'a Path>,
 }
 
 impl<'a> Dot<'a> {
-  pub fn new(name: &'a str, src: &'a str, dest: &'a str)
-    -> Result<Self, Error>
+  /// Builds a dot, failing with every problem found with it.
+  pub fn new(name: &'a str, srcs: &'a [String], dests: &'a [String])
+    -> Result<Self, Vec<Error>>
   {
-    let src = Path::new(src);
-    if !src.exists() {
-      return Err(Error::nonex(name, src));
+    let mut errs = vec![];
+
+    let srcs: Vec<_> = srcs.iter().map(Path::new).collect();
+    for src in srcs.iter().filter(|src| !src.exists()) {
+      errs.push(Error::nonex(name, src));
+    }
+
+    if srcs.is_empty() {
+      errs.push(Error {kind: ErrKind::NoSrc, name: name.to_string()});
+    }
+
+    if dests.is_empty() {
+      errs.push(Error {kind: ErrKind::NoDest, name: name.to_string()});
+    }
+
+    // Missing srcs can't be compared against dests.
+    let found: Vec<_> = srcs.iter().copied().filter(|src| src.exists()).collect();
+    let mut dests_ = vec![];
+    for dest in dests {
+      match Self::dest(name, &found, dest) {
+        Err(err) => errs.push(err),
+        Ok(dest) => dests_.push(dest),
+      }
+    }
+    let dests = dests_;
+
+    for (i, a) in dests.iter().enumerate() {
+      for b in &dests[i + 1..] {
+        let (a, b) = (a.path(), b.path());
+        if a.starts_with(b) || b.starts_with(a) {
+          errs.push(Error::overlap(name, a, b));
+        }
+      }
     }
 
+    if !errs.is_empty() {
+      return Err(errs);
+    }
+
+    Ok(Dot {
+      absolute: None,
+      depth: None,
+      dests,
+      follow: None,
+      ignore: None,
+      mode: None,
+      modes: None,
+      name,
+      rage: None,
+      srcs,
+    })
+  }
+
+  fn dest(name: &str, srcs: &[&Path], dest: &'a str)
+    -> Result<Sh<'a>, Error>
+  {
     let dest = match Sh::try_from(dest) {
       Err(err) => return Err(Error::sh(name, err)),
       Ok(sh) => sh,
     };
 
-    let dest_ = match &dest {
-      Sh::Expanded {buf, ..} => buf,
-      Sh::Normal(p) => *p,
-    };
-
+    let dest_ = dest.path();
     if dest_.exists() {
-      match is_same_file(dest_, src) {
-        Err(err) => return Err(Error::io(name, err)),
-        Ok(false) => (),
-        Ok(true) => return Err(Error::same(name, dest_)),
+      for src in srcs {
+        match is_same_file(dest_, src) {
+          Err(err) => return Err(Error::io(name, err)),
+          Ok(false) => (),
+          Ok(true) => return Err(Error::same(name, dest_)),
+        }
       }
     }
 
-    Ok(Dot {dest, name, src})
+    Ok(dest)
   }
 }
 
 #[derive(Debug)]
 pub enum ErrKind {
+  DepCycle(Vec<String>),
   IoErr(io::Error),
+  MissingDep(String),
+  NoDest,
+  NoSrc,
   NonexistentSrc(PathBuf),
+  Overlap(PathBuf, PathBuf),
   SameFile(PathBuf),
   ShErr(ShErr),
 }
@@ -69,6 +136,15 @@ impl Error {
     }
   }
 
+  pub fn overlap<P, Q>(name: &str, a: P, b: Q) -> Self
+    where P: AsRef<Path>, Q: AsRef<Path>
+  {
+    Error {
+      kind: ErrKind::Overlap(a.as_ref().to_path_buf(), b.as_ref().to_path_buf()),
+      name: name.to_string(),
+    }
+  }
+
   pub fn same<P>(name: &str, p: P) -> Self where P: AsRef<Path> {
     Error {
       kind: ErrKind::SameFile(p.as_ref().to_path_buf()),
@@ -85,11 +161,44 @@ impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     let s: String;
     let msg: &dyn fmt::Display = match &self.kind {
+      ErrKind::DepCycle(names) => {
+        s = match names.is_empty() {
+          false => format!(
+            "dependency cycle through {}",
+            names.iter()
+              .map(|name| format!("\"{}\"", name))
+              .collect::<Vec<_>>()
+              .join(", "),
+          ),
+          true => "dot depends on itself".to_owned(),
+        };
+        &s
+      }
       ErrKind::IoErr(err) => err,
+      ErrKind::MissingDep(dep) => {
+        s = format!("required dot \"{}\" doesn't exist", dep);
+        &s
+      }
+      ErrKind::NoDest => {
+        s = "no destination paths".to_owned();
+        &s
+      }
+      ErrKind::NoSrc => {
+        s = "no source paths".to_owned();
+        &s
+      }
       ErrKind::NonexistentSrc(src) => {
         s = format!("nonexistent source file \"{}\"", src.display());
         &s
       }
+      ErrKind::Overlap(a, b) => {
+        s = format!(
+          "destination paths \"{}\" and \"{}\" overlap",
+          a.display(),
+          b.display(),
+        );
+        &s
+      }
       ErrKind::SameFile(p) => {
         s = format!(
           "source and destination paths refers to same file \"{}\"",
@@ -27,6 +27,15 @@ pub enum Sh<'a> {
   Normal(&'a Path),
 }
 
+impl Sh<'_> {
+  pub fn path(&self) -> &Path {
+    match self {
+      Sh::Expanded {buf, ..} => buf,
+      Sh::Normal(p) => p,
+    }
+  }
+}
+
 impl<'a> TryFrom<&'a str> for Sh<'a> {
   type Error = Error;
 
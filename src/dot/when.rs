@@ -0,0 +1,285 @@
+use serde::Deserialize;
+use std::{
+  env::{split_paths, var, var_os},
+  error,
+  fmt,
+  iter::Peekable,
+  os::unix::fs::PermissionsExt,
+  path::Path,
+  str::CharIndices,
+};
+use super::super::host::HOST;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+  Expected(&'static str, usize),
+  Unexpected(char, usize),
+  Unterminated(usize),
+  UnknownVar(String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Expected(what, col) => {
+        write!(f, "expected {} at column {}", what, col)
+      }
+      Error::Unexpected(c, col) => {
+        write!(f, "unexpected '{}' at column {}", c, col)
+      }
+      Error::Unterminated(col) => {
+        write!(f, "unterminated string starting at column {}", col)
+      }
+      Error::UnknownVar(name) => write!(f, "unknown variable \"{}\"", name),
+    }
+  }
+}
+
+impl error::Error for Error {}
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Expr {
+  And(Box<Expr>, Box<Expr>),
+  Eq(String, String),
+  Has(String),
+  Ne(String, String),
+  Not(Box<Expr>),
+  Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+  fn eval(&self) -> bool {
+    match self {
+      Expr::And(a, b) => a.eval() && b.eval(),
+      Expr::Eq(name, val) => fact(name) == *val,
+      Expr::Has(cmd) => has(cmd),
+      Expr::Ne(name, val) => fact(name) != *val,
+      Expr::Not(e) => !e.eval(),
+      Expr::Or(a, b) => a.eval() || b.eval(),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Tok {
+  And,
+  Close,
+  Eq,
+  Ident(String),
+  Ne,
+  Not,
+  Open,
+  Or,
+  Str(String),
+}
+
+/// Condition telling whether a dot should be shoved on the current machine,
+/// such as `os == 'linux' && has('nvim')`.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(try_from = "String")]
+pub struct When {
+  expr: Expr,
+  s: String,
+}
+
+impl When {
+  pub fn eval(&self) -> bool {
+    self.expr.eval()
+  }
+}
+
+impl fmt::Display for When {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.s)
+  }
+}
+
+impl TryFrom<String> for When {
+  type Error = Error;
+
+  fn try_from(s: String) -> Result<Self, Self::Error> {
+    let mut parser = Parser {end: s.chars().count() + 1, pos: 0, toks: lex(&s)?};
+    let expr = parser.or()?;
+    match parser.toks.get(parser.pos) {
+      None => Ok(When {expr, s}),
+      Some((col, _)) => Err(Error::Expected("end of condition", *col)),
+    }
+  }
+}
+
+struct Parser {
+  end: usize,
+  pos: usize,
+  toks: Vec<(usize, Tok)>,
+}
+
+impl Parser {
+  fn and(&mut self) -> Result<Expr, Error> {
+    let mut e = self.unary()?;
+    while self.eat(&Tok::And) {
+      e = Expr::And(Box::new(e), Box::new(self.unary()?));
+    }
+    Ok(e)
+  }
+
+  fn col(&self) -> usize {
+    self.toks.get(self.pos).map_or(self.end, |(col, _)| *col)
+  }
+
+  fn eat(&mut self, tok: &Tok) -> bool {
+    let eaten = self.toks.get(self.pos).is_some_and(|(_, t)| t == tok);
+    if eaten {
+      self.pos += 1;
+    }
+    eaten
+  }
+
+  fn expect(&mut self, tok: Tok, what: &'static str) -> Result<(), Error> {
+    match self.eat(&tok) {
+      false => Err(Error::Expected(what, self.col())),
+      true => Ok(()),
+    }
+  }
+
+  fn next(&mut self) -> Option<Tok> {
+    let tok = self.toks.get(self.pos)?.1.clone();
+    self.pos += 1;
+    Some(tok)
+  }
+
+  fn or(&mut self) -> Result<Expr, Error> {
+    let mut e = self.and()?;
+    while self.eat(&Tok::Or) {
+      e = Expr::Or(Box::new(e), Box::new(self.and()?));
+    }
+    Ok(e)
+  }
+
+  fn string(&mut self) -> Result<String, Error> {
+    let col = self.col();
+    match self.next() {
+      Some(Tok::Str(s)) => Ok(s),
+      _ => Err(Error::Expected("string", col)),
+    }
+  }
+
+  fn unary(&mut self) -> Result<Expr, Error> {
+    if self.eat(&Tok::Not) {
+      return Ok(Expr::Not(Box::new(self.unary()?)));
+    }
+
+    if self.eat(&Tok::Open) {
+      let e = self.or()?;
+      self.expect(Tok::Close, "')'")?;
+      return Ok(e);
+    }
+
+    let col = self.col();
+    match self.next() {
+      Some(Tok::Ident(name)) if name == "has" => {
+        self.expect(Tok::Open, "'('")?;
+        let cmd = self.string()?;
+        self.expect(Tok::Close, "')'")?;
+        Ok(Expr::Has(cmd))
+      }
+      Some(Tok::Ident(name)) => {
+        let known = matches!(&*name, "arch" | "host" | "hostname" | "os" | "user")
+          || name.strip_prefix("env.").is_some_and(|var| !var.is_empty());
+        if !known {
+          return Err(Error::UnknownVar(name));
+        }
+
+        let col = self.col();
+        match self.next() {
+          Some(Tok::Eq) => Ok(Expr::Eq(name, self.string()?)),
+          Some(Tok::Ne) => Ok(Expr::Ne(name, self.string()?)),
+          _ => Err(Error::Expected("'==' or '!='", col)),
+        }
+      }
+      _ => Err(Error::Expected("condition", col)),
+    }
+  }
+}
+
+/// Whether `p` is an executable file.
+fn executable(p: &Path) -> bool {
+  p.metadata().is_ok_and(|meta| {
+    meta.is_file() && meta.permissions().mode() & 0o111 != 0
+  })
+}
+
+/// Value of a variable, where unset environment variables are empty.
+fn fact(name: &str) -> String {
+  match name {
+    "arch" => HOST.arch.to_owned(),
+    "host" | "hostname" => HOST.hostname.clone(),
+    "os" => HOST.os.to_owned(),
+    "user" => HOST.user.clone(),
+    _ => var(&name["env.".len()..]).unwrap_or_default(),
+  }
+}
+
+/// Whether `cmd` is an executable found on `PATH`.
+fn has(cmd: &str) -> bool {
+  if cmd.contains('/') {
+    return executable(Path::new(cmd));
+  }
+  var_os("PATH").is_some_and(|paths| {
+    split_paths(&paths).any(|dir| executable(&dir.join(cmd)))
+  })
+}
+
+fn lex(s: &str) -> Result<Vec<(usize, Tok)>, Error> {
+  let mut toks = vec![];
+  let mut chars = s.char_indices().peekable();
+  let col = |i: usize| s[..i].chars().count() + 1;
+
+  while let Some((i, c)) = chars.next() {
+    let tok = match c {
+      c if c.is_whitespace() => continue,
+      '(' => Tok::Open,
+      ')' => Tok::Close,
+      '!' => match chars.next_if(|(_, c)| *c == '=') {
+        None => Tok::Not,
+        Some(_) => Tok::Ne,
+      },
+      '=' | '&' | '|' => match chars.next_if(|(_, d)| *d == c) {
+        None => return Err(Error::Unexpected(c, col(i))),
+        Some(_) => match c {
+          '=' => Tok::Eq,
+          '&' => Tok::And,
+          _ => Tok::Or,
+        },
+      },
+      '\'' | '"' => {
+        let start = i + 1;
+        match chars.find(|(_, d)| *d == c) {
+          None => return Err(Error::Unterminated(col(i))),
+          Some((end, _)) => Tok::Str(s[start..end].to_owned()),
+        }
+      }
+      c if ident(c) => Tok::Ident(word(s, i, &mut chars)),
+      c => return Err(Error::Unexpected(c, col(i))),
+    };
+    toks.push((col(i), tok));
+  }
+
+  Ok(toks)
+}
+
+fn ident(c: char) -> bool {
+  c.is_alphanumeric() || matches!(c, '_' | '.' | '-')
+}
+
+/// Consumes the rest of the word starting at `start`.
+fn word(s: &str, start: usize, chars: &mut Peekable<CharIndices>) -> String {
+  let mut end = s.len();
+  while let Some(&(i, c)) = chars.peek() {
+    if !ident(c) {
+      end = i;
+      break;
+    }
+    chars.next();
+  }
+  s[start..end].to_owned()
+}
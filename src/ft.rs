@@ -1,12 +1,25 @@
 use same_file::is_same_file;
+use sha2::{Digest, Sha256};
 use std::{
   env::current_dir as cd,
   error,
   fmt,
   io,
-  path::Path,
-  fs::{remove_file, remove_dir, remove_dir_all},
+  path::{Component, Path, PathBuf},
+  fs::{
+    File,
+    copy,
+    create_dir,
+    create_dir_all,
+    remove_dir,
+    remove_dir_all,
+    remove_file,
+    rename,
+    set_permissions,
+  },
+  os::unix::fs::{MetadataExt, symlink},
 };
+use walkdir::WalkDir;
 
 #[derive(Debug)]
 pub enum Error {
@@ -16,10 +29,9 @@ pub enum Error {
 
 impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    let err: &dyn fmt::Display;
-    match self {
-      Error::Io(io_err) => err = io_err,
-      Error::Rage(rage_err) => err = rage_err,
+    let err: &dyn fmt::Display = match self {
+      Error::Io(io_err) => io_err,
+      Error::Rage(rage_err) => rage_err,
     };
     write!(f, "{}", err)
   }
@@ -46,13 +58,25 @@ pub struct Ft<'a> {
 }
 
 impl<'a> Ft<'a> {
-  fn check_rage(&self, lvl: usize, min: usize) -> Result<(), RageErr> {
+  fn check_rage(&self, lvl: usize) -> Result<(), RageErr> {
+    let min = self.ty.rage();
     match lvl >= min {
       false => Err(RageErr::new(self.ty, lvl, min)),
       true => Ok(()),
     }
   }
 
+  fn kind(dest: &Path) -> io::Result<Type> {
+    Ok(if dest.is_dir() {
+      match dest.read_dir()?.count() == 0 {
+        false => Type::NonemptyDir,
+        true => Type::EmptyDir,
+      }
+    } else {
+      Type::File
+    })
+  }
+
   pub fn new<P>(src: &P, dest: &'a Path) -> io::Result<Self>
     where P: AsRef<Path> + ?Sized
   {
@@ -72,43 +96,215 @@ impl<'a> Ft<'a> {
           true => Type::Dotlink,
         },
       }
-    } else if dest.is_dir() {
-      match dest.read_dir()?.count() == 0 {
-        false => Type::NonemptyDir,
-        true => Type::EmptyDir,
+    } else if dest.is_file() && is_same_file(dest, src)? {
+      // The dotfile itself may be reached through a symlinked parent dir.
+      match hardlinked(src, dest)? {
+        false => Self::kind(dest)?,
+        true => Type::Hardlink,
       }
+    } else if dest.is_file() && src.is_file() && same_content(src, dest)? {
+      Type::Dotcopy
     } else {
-      Type::File
+      Self::kind(dest)?
     };
     Ok(Ft {path: dest, ty})
   }
 
+  /// Classifies `dest` given the `hash` of the content it was copied with by
+  /// a previous run, regardless of whether its source still exists.
+  pub fn copied(hash: &str, dest: &'a Path) -> io::Result<Self> {
+    let ty = if dest.is_symlink() {
+      Type::Symlink
+    } else if dest.is_file() && self::hash(dest)? == hash {
+      Type::Dotcopy
+    } else {
+      Self::kind(dest)?
+    };
+    Ok(Ft {path: dest, ty})
+  }
+
+  /// Classifies `dest` given the device and inode numbers it had when it was
+  /// hardlinked by a previous run, regardless of whether its source still
+  /// exists.
+  pub fn hardlinked(id: (u64, u64), dest: &'a Path) -> io::Result<Self> {
+    let ty = if dest.is_symlink() {
+      Type::Symlink
+    } else if dest.is_file() && file_id(dest)? == id {
+      Type::Hardlink
+    } else {
+      Self::kind(dest)?
+    };
+    Ok(Ft {path: dest, ty})
+  }
+
+  /// Classifies `dest` if it's a dangling symlink, in which case it's deemed a
+  /// dotlink if it points somewhere inside any of the `roots`.
+  pub fn dangling(roots: &[&Path], dest: &'a Path) -> io::Result<Option<Self>> {
+    if !dest.is_symlink() || dest.exists() {
+      return Ok(None);
+    }
+
+    let base = cd()?.join(dest);
+    let link = normalize(&base.parent().unwrap().join(dest.read_link()?));
+    let mut inside = false;
+    for root in roots {
+      inside |= link.starts_with(normalize(&cd()?.join(root)))
+        || link.starts_with(root.canonicalize()?);
+    }
+
+    let ty = match inside {
+      false => Type::Symlink,
+      true => Type::Dotlink,
+    };
+    Ok(Some(Ft {path: dest, ty}))
+  }
+
+  /// Classifies `dest` given the `target` it was linked to by a previous run,
+  /// regardless of whether `target` still exists.
+  pub fn recorded<P>(target: &P, dest: &'a Path) -> io::Result<Self>
+    where P: AsRef<Path> + ?Sized
+  {
+    let ty = if dest.is_symlink() {
+      match dest.read_link()? == target.as_ref() {
+        false => Type::Symlink,
+        true => Type::Dotlink,
+      }
+    } else {
+      Self::kind(dest)?
+    };
+    Ok(Ft {path: dest, ty})
+  }
+
+  /// Moves the file out of the way into `to`, as long as the rage level would
+  /// be enough to remove it.
+  pub fn backup<P>(&self, rage: usize, to: P) -> Result<(), Error>
+    where P: AsRef<Path>
+  {
+    self.check_rage(rage)?;
+    let to = to.as_ref();
+    // Renaming would silently replace whatever is already there.
+    if to.symlink_metadata().is_ok() {
+      let msg = format!("backup path \"{}\" already exists", to.display());
+      return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+    }
+    if let Some(parent) = to.parent() {
+      create_dir_all(parent)?;
+    }
+    mv(self.path, to)?;
+    Ok(())
+  }
+
   pub fn rm(&self, rage: usize) -> Result<(), Error> {
+    self.check_rage(rage)?;
     match self.ty {
-      Type::Dotlink => {
-        remove_file(self.path)?;
-      }
-      Type::EmptyDir => {
-        self.check_rage(rage, 2)?;
-        remove_dir(self.path)?;
-      }
-      Type::File => {
-        self.check_rage(rage, 2)?;
-        remove_file(self.path)?;
-      }
-      Type::NonemptyDir => {
-        self.check_rage(rage, 3)?;
-        remove_dir_all(self.path)?;
-      }
-      Type::Symlink => {
-        self.check_rage(rage, 1)?;
-        remove_file(self.path)?;
-      }
+      Type::Dotcopy
+        | Type::Dotlink
+        | Type::File
+        | Type::Hardlink
+        | Type::Symlink => remove_file(self.path)?,
+      Type::Dotdir | Type::EmptyDir => remove_dir(self.path)?,
+      Type::NonemptyDir => remove_dir_all(self.path)?,
     }
     Ok(())
   }
 }
 
+/// Hex encoded SHA-256 hash of the content of the file at `p`.
+pub fn hash(p: &Path) -> io::Result<String> {
+  let mut hasher = Sha256::new();
+  io::copy(&mut File::open(p)?, &mut hasher)?;
+  Ok(hex(&hasher.finalize()))
+}
+
+/// Hex encoded SHA-256 hash of `bytes`.
+pub fn digest(bytes: &[u8]) -> String {
+  hex(&Sha256::digest(bytes))
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Device and inode numbers of the file at `p`.
+pub fn file_id(p: &Path) -> io::Result<(u64, u64)> {
+  let meta = p.metadata()?;
+  Ok((meta.dev(), meta.ino()))
+}
+
+/// Whether `a` and `b` are distinct links to the same file.
+fn hardlinked(a: &Path, b: &Path) -> io::Result<bool> {
+  Ok(a.canonicalize()? != b.canonicalize()? && b.metadata()?.nlink() > 1)
+}
+
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+  Ok(a.metadata()?.len() == b.metadata()?.len() && hash(a)? == hash(b)?)
+}
+
+/// Moves `from` into `to`, copying it over when they're on different
+/// filesystems.
+pub fn mv(from: &Path, to: &Path) -> io::Result<()> {
+  match rename(from, to) {
+    Err(err) if err.kind() == io::ErrorKind::CrossesDevices => (),
+    res => return res,
+  }
+
+  if from.is_symlink() {
+    symlink(from.read_link()?, to)?;
+    return remove_file(from);
+  }
+
+  if !from.is_dir() {
+    copy(from, to)?;
+    return remove_file(from);
+  }
+
+  for entry in WalkDir::new(from) {
+    let entry = entry?;
+    let dest = to.join(entry.path().strip_prefix(from).unwrap());
+    if entry.path_is_symlink() {
+      symlink(entry.path().read_link()?, dest)?;
+    } else if entry.file_type().is_dir() {
+      create_dir(&dest)?;
+      set_permissions(&dest, entry.metadata()?.permissions())?;
+    } else {
+      copy(entry.path(), dest)?;
+    }
+  }
+  remove_dir_all(from)
+}
+
+/// Atomically replaces `dest` with a symlink to `target`, by renaming a
+/// temporary symlink created next to it over it.
+pub fn relink(target: &Path, dest: &Path) -> io::Result<()> {
+  let mut name = dest.file_name().unwrap().to_owned();
+  name.push(".shove-tmp");
+  let tmp = dest.with_file_name(name);
+
+  match remove_file(&tmp) {
+    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+    _ => (),
+  }
+
+  symlink(target, &tmp)?;
+  rename(&tmp, dest).inspect_err(|_| {
+    let _ = remove_file(&tmp);
+  })
+}
+
+/// Lexically resolves `.` and `..` components of `p`, since dangling paths
+/// can't be canonicalized.
+fn normalize(p: &Path) -> PathBuf {
+  let mut buf = PathBuf::new();
+  for c in p.components() {
+    match c {
+      Component::CurDir => (),
+      Component::ParentDir => {buf.pop();}
+      c => buf.push(c),
+    }
+  }
+  buf
+}
+
 #[derive(Clone, Debug,  Eq, Ord, PartialEq, PartialOrd)]
 pub struct RageErr {
   ty: Type,
@@ -138,19 +334,39 @@ impl error::Error for RageErr {}
 
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum Type {
+  Dotcopy,
+  /// Empty dir created by Shove.
+  Dotdir,
   Dotlink,
   EmptyDir,
   File,
+  /// Hardlink to a dotfile.
+  Hardlink,
   NonemptyDir,
   Symlink,
 }
 
+impl Type {
+  /// Minimum rage level required to remove a file of this type.
+  pub fn rage(&self) -> usize {
+    match self {
+      Type::Dotcopy | Type::Dotdir | Type::Dotlink | Type::Hardlink => 0,
+      Type::Symlink => 1,
+      Type::EmptyDir | Type::File => 2,
+      Type::NonemptyDir => 3,
+    }
+  }
+}
+
 impl fmt::Display for Type {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     let s = match self {
+      Type::Dotcopy => "dotfile copy",
+      Type::Dotdir => "empty dotfile dir",
       Type::Dotlink => "dotfile",
       Type::EmptyDir => "empty dir",
       Type::File => "file",
+      Type::Hardlink => "dotfile hardlink",
       Type::NonemptyDir => "non-empty dir",
       Type::Symlink => "symlink",
     };
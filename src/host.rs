@@ -0,0 +1,47 @@
+use lazy_static::lazy_static;
+use std::{
+  env::{consts::{ARCH, OS}, var},
+  ffi::CStr,
+  os::raw::c_char,
+};
+
+lazy_static! {
+  pub static ref HOST: Host = Host::new();
+}
+
+/// Facts about the machine Shove is running on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Host {
+  pub arch: &'static str,
+  pub hostname: String,
+  pub os: &'static str,
+  pub user: String,
+}
+
+impl Host {
+  fn new() -> Self {
+    Host {arch: ARCH, hostname: hostname(), os: OS, user: user()}
+  }
+}
+
+fn hostname() -> String {
+  let mut buf = [0 as c_char; 256];
+  match unsafe {libc::gethostname(buf.as_mut_ptr(), buf.len())} {
+    0 => unsafe {CStr::from_ptr(buf.as_ptr())}.to_string_lossy().into_owned(),
+    _ => String::new(),
+  }
+}
+
+fn user() -> String {
+  if let Ok(user) = var("USER").or_else(|_| var("LOGNAME")) {
+    return user;
+  }
+
+  let pw = unsafe {libc::getpwuid(libc::geteuid())};
+  match pw.is_null() {
+    false => unsafe {CStr::from_ptr((*pw).pw_name)}
+      .to_string_lossy()
+      .into_owned(),
+    true => String::new(),
+  }
+}
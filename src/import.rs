@@ -0,0 +1,416 @@
+use ::log::{error, info, warn};
+use lazy_static::lazy_static;
+use regex::{escape, Regex};
+use std::{
+  collections::HashSet,
+  fs::{read_dir, set_permissions, DirEntry, Permissions},
+  io,
+  os::unix::fs::PermissionsExt,
+  path::{Path, PathBuf},
+  process::exit,
+};
+use super::{
+  cli::Tool,
+  ft::mv,
+  init::{ensure_free, save},
+  journal::{Entry, Journal},
+};
+use toml::{map::Map, Value};
+
+/// Prefixes of chezmoi source names which can't be shoved, along with why.
+const UNSUPPORTED: &[(&str, &str)] = &[
+  ("create_", "it's only created if missing"),
+  ("encrypted_", "it's encrypted"),
+  ("external_", "it's an external dir"),
+  ("modify_", "it's a script modifying its target"),
+  ("remove_", "its target is to be removed"),
+  ("run_", "it's a script"),
+  ("symlink_", "it's a symlink whose target is its contents"),
+];
+
+/// File or dir of a chezmoi source dir, as named by its target.
+#[derive(Debug, Default)]
+struct Attrs {
+  executable: bool,
+  name: String,
+  private: bool,
+  readonly: bool,
+}
+
+impl Attrs {
+  /// Parses the chezmoi source name `name`, failing with why it can't be
+  /// shoved, if worth telling.
+  fn parse(name: &str, dir: bool) -> Result<Self, Option<&'static str>> {
+    if name.starts_with(".chezmoi") {
+      return Err(Some("it's specific to chezmoi"));
+    }
+    // Hidden files, such as ".git", are ignored by chezmoi itself.
+    if name.starts_with('.') {
+      return Err(None);
+    }
+
+    let mut attrs = Attrs::default();
+    let mut rest = name;
+    loop {
+      let unsupported = UNSUPPORTED.iter().find(|(p, _)| rest.starts_with(p));
+      if let Some((_, why)) = unsupported {
+        return Err(Some(why));
+      }
+
+      let (prefix, tail) = match rest.split_once('_') {
+        Some((prefix, tail)) => (prefix, tail),
+        None => break,
+      };
+      match prefix {
+        "dot" => {
+          attrs.name = format!(".{}", tail);
+          return attrs.suffixed(dir);
+        }
+        "empty" | "once" | "onchange" | "before" | "after" => (),
+        "exact" if dir => warn!("extra files in \"{}\" won't be removed", name),
+        "executable" => attrs.executable = true,
+        "literal" => {
+          attrs.name = tail.to_owned();
+          return Ok(attrs);
+        }
+        "private" => attrs.private = true,
+        "readonly" => attrs.readonly = true,
+        _ => break,
+      }
+      rest = tail;
+    }
+
+    attrs.name = rest.to_owned();
+    attrs.suffixed(dir)
+  }
+
+  /// Handles the suffixes of the name of a file.
+  fn suffixed(mut self, dir: bool) -> Result<Self, Option<&'static str>> {
+    if dir {
+      return Ok(self);
+    }
+    if let Some(name) = self.name.strip_suffix(".literal") {
+      self.name = name.to_owned();
+    } else if self.name.ends_with(".tmpl") {
+      return Err(Some("it's a chezmoi template"));
+    }
+    Ok(self)
+  }
+
+  /// Mode of the file once imported, given its current one.
+  fn mode(&self, mut mode: u32) -> u32 {
+    if self.executable {
+      mode |= 0o111;
+    }
+    if self.private {
+      mode &= !0o077;
+    }
+    if self.readonly {
+      mode &= !0o222;
+    }
+    mode
+  }
+}
+
+/// Changes to be made to an imported tree so that it can be shoved.
+#[derive(Debug, Default)]
+struct Import {
+  /// Paths to be ignored by the dot being imported, relative to the current
+  /// dir once renamed.
+  ignore: Vec<PathBuf>,
+
+  /// Modes to be set, by path once renamed.
+  modes: Vec<(PathBuf, u32)>,
+
+  /// Files to be renamed, with parents before their children.
+  renames: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Import {
+  /// Plans the import of the chezmoi source dir `dir`, whose files end up
+  /// at `at` once renamed.
+  fn chezmoi(&mut self, dir: &Path, at: &Path) -> io::Result<()> {
+    // Names taken by files of the dir, which several ones may map to.
+    let mut taken = HashSet::new();
+
+    for entry in entries(dir)? {
+      let (path, name) = (entry.path(), entry.file_name());
+      let is_dir = entry.file_type()?.is_dir();
+
+      // Names which aren't valid Unicode can't have any attribute.
+      let attrs = match name.to_str() {
+        None => Ok(Attrs::default()),
+        Some(name) => Attrs::parse(name, is_dir),
+      };
+      let attrs = match attrs {
+        Err(why) => {
+          if let Some(why) = why {
+            warn!("unable to import \"{}\": {}", path.display(), why);
+          }
+          self.ignore.push(at.join(&name));
+          continue;
+        }
+        Ok(attrs) => attrs,
+      };
+
+      let renamed = match attrs.name.is_empty() {
+        false => attrs.name.as_ref(),
+        true => name.as_os_str(),
+      };
+      let to = path.with_file_name(renamed);
+      let clash = !taken.insert(renamed.to_owned())
+        || (to != path && to.symlink_metadata().is_ok());
+      if clash {
+        warn!(
+          "unable to import \"{}\": \"{}\" is taken by another file",
+          path.display(),
+          to.display(),
+        );
+        self.ignore.push(at.join(&name));
+        continue;
+      }
+
+      let target = at.join(renamed);
+      if to != path {
+        self.renames.push((path.clone(), to));
+      }
+
+      let mode = entry.metadata()?.permissions().mode() & 0o7777;
+      if attrs.mode(mode) != mode && !entry.file_type()?.is_symlink() {
+        self.modes.push((target.clone(), attrs.mode(mode)));
+      }
+
+      if is_dir {
+        self.chezmoi(&path, &target)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Plans the import of the Stow package dir `dir`, whose files end up at
+  /// `at` once renamed. Names starting with "dot-" are renamed if `dotfiles`.
+  fn stow(&mut self, dir: &Path, at: &Path, dotfiles: bool, top: bool)
+    -> io::Result<()>
+  {
+    lazy_static! {
+      // Stow's default ignore list, used unless a package has its own.
+      static ref IGNORED: Regex = Regex::new(concat!(
+        r"^(RCS|.+,v|CVS|\.#.+|\.cvsignore|\.svn|_darcs|\.hg|\.git|",
+        r"\.gitignore|\.gitmodules|.+~|#.*#)$",
+      )).unwrap();
+      static ref IGNORED_TOP: Regex =
+        Regex::new(r"^(README.*|LICENSE.*|COPYING)$").unwrap();
+    }
+
+    let local = top && dir.join(".stow-local-ignore").exists();
+    if local {
+      warn!(
+        "unable to import \"{}\": its patterns have to be set as the ignore \
+         regexes of the dot by hand",
+        dir.join(".stow-local-ignore").display(),
+      );
+    }
+
+    for entry in entries(dir)? {
+      let (path, name) = (entry.path(), entry.file_name());
+      let name_s = name.to_string_lossy();
+
+      let ignored = match local {
+        false => {
+          IGNORED.is_match(&name_s) || (top && IGNORED_TOP.is_match(&name_s))
+        }
+        true => top && name_s == ".stow-local-ignore",
+      };
+      if ignored {
+        self.ignore.push(at.join(&name));
+        continue;
+      }
+
+      let renamed = match name_s.strip_prefix("dot-").filter(|_| dotfiles) {
+        None => name.clone(),
+        Some(rest) => format!(".{}", rest).into(),
+      };
+      let target = at.join(&renamed);
+      if renamed != name {
+        let to = path.with_file_name(&renamed);
+        if to.symlink_metadata().is_ok() {
+          warn!(
+            "unable to import \"{}\": \"{}\" is taken by another file",
+            path.display(),
+            to.display(),
+          );
+          self.ignore.push(at.join(&name));
+          continue;
+        }
+        self.renames.push((path.clone(), to));
+      }
+
+      if entry.file_type()?.is_dir() {
+        self.stow(&path, &target, dotfiles, false)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Info of a dot with the dotfiles at `src` shoved into `dest`.
+  fn dot(&self, name: &str, src: &Path, dest: String) -> Value {
+    let src = src.to_string_lossy().into_owned();
+    if self.ignore.is_empty() && src == name {
+      return Value::String(dest);
+    }
+
+    let mut t = Map::new();
+    t.insert("src".to_owned(), Value::String(src));
+    t.insert("dest".to_owned(), Value::String(dest));
+    if !self.ignore.is_empty() {
+      let ignore = self.ignore.iter()
+        .map(|p| Value::String(format!("^{}$", escape(&p.to_string_lossy()))))
+        .collect();
+      t.insert("ignore".to_owned(), Value::Array(ignore));
+    }
+    Value::Table(t)
+  }
+}
+
+/// Applies the changes needed by every import, recording them in the
+/// journal, or only shows them if `no`.
+fn apply(imports: &[Import], no: bool) -> io::Result<()> {
+  let renames = imports.iter().flat_map(|import| import.renames.iter().rev());
+  let modes = imports.iter().flat_map(|import| &import.modes);
+
+  let idle = imports.iter()
+    .all(|import| import.renames.is_empty() && import.modes.is_empty());
+  if idle {
+    return Ok(());
+  }
+
+  // Starting a journal would discard the one of the last run, which could
+  // then no longer be undone.
+  if !Journal::load()?.entries.is_empty() {
+    let msg = format!(
+      "the last run could no longer be undone, either undo it first or \
+      remove \"{}\" to keep its changes",
+      Journal::path().display(),
+    );
+    match no {
+      false => return Err(io::Error::other(msg)),
+      true => warn!("{}", msg),
+    }
+  }
+
+  if no {
+    for (from, to) in renames {
+      info!("would rename \"{}\" into \"{}\"", from.display(), to.display());
+    }
+    for (path, mode) in modes {
+      info!("would set mode of \"{}\" to {:o}", path.display(), mode);
+    }
+    return Ok(());
+  }
+
+  let mut journal = Journal::begin()?;
+
+  // Children are renamed before their parents, and modes are set once every
+  // file is in place, in case some dir loses its write permission.
+  for (from, to) in renames {
+    info!("rename \"{}\" into \"{}\"", from.display(), to.display());
+    mv(from, to)?;
+    journal.push(Entry::Moved {from: from.clone(), to: to.clone()})?;
+  }
+  for (path, mode) in modes {
+    info!("set mode of \"{}\" to {:o}", path.display(), mode);
+    let old = path.metadata()?.permissions().mode() & 0o7777;
+    set_permissions(path, Permissions::from_mode(*mode))?;
+    journal.push(Entry::Mode {path: path.clone(), mode: old})?;
+  }
+  Ok(())
+}
+
+/// Entries of the dir `dir`, sorted by name.
+fn entries(dir: &Path) -> io::Result<Vec<DirEntry>> {
+  let mut entries = read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+  entries.sort_by_key(DirEntry::file_name);
+  Ok(entries)
+}
+
+/// Path of `p` starting with "~" if it's within the home dir.
+fn tilde(p: &Path) -> String {
+  let home = PathBuf::from(shellexpand::tilde("~").as_ref());
+  match p.strip_prefix(&home) {
+    Err(_) => p.to_string_lossy().into_owned(),
+    Ok(rest) if rest.as_os_str().is_empty() => "~".to_owned(),
+    Ok(rest) => format!("~/{}", rest.display()),
+  }
+}
+
+/// Writes a config file with the dots of the tree at `dir` managed by `tool`,
+/// renaming files where needed.
+pub fn import(
+  tool: Tool,
+  dir: &Path,
+  target: Option<&Path>,
+  dotfiles: bool,
+  force: bool,
+  no: bool,
+) {
+  ensure_free(force);
+  if let Err(err) = import_tree(tool, dir, target, dotfiles, no) {
+    error!("unable to import \"{}\": {}", dir.display(), err);
+    exit(1);
+  }
+}
+
+fn import_tree(
+  tool: Tool,
+  dir: &Path,
+  target: Option<&Path>,
+  dotfiles: bool,
+  no: bool,
+) -> io::Result<()> {
+  let mut dots = Map::new();
+  let mut imports = vec![];
+
+  match tool {
+    Tool::Chezmoi => {
+      let name = dir.canonicalize()?
+        .file_name()
+        .map_or("chezmoi".to_owned(), |name| name.to_string_lossy().into());
+      let mut import = Import::default();
+      import.chezmoi(dir, dir)?;
+      let dest = target.map_or("~".to_owned(), |p| p.display().to_string());
+      dots.insert(name.clone(), import.dot(&name, dir, dest));
+      imports.push(import);
+    }
+    Tool::Stow => {
+      // Stow shoves packages into the parent of the stow dir by default.
+      let dest = match target {
+        Some(target) => target.display().to_string(),
+        None => match dir.canonicalize()?.parent() {
+          None => "/".to_owned(),
+          Some(parent) => tilde(parent),
+        },
+      };
+
+      for entry in entries(dir)? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !entry.file_type()?.is_dir() || name.starts_with('.') {
+          continue;
+        }
+        let src = entry.path();
+        let mut import = Import::default();
+        import.stow(&src, &src, dotfiles, true)?;
+        dots.insert(name.clone(), import.dot(&name, &src, dest.clone()));
+        imports.push(import);
+      }
+    }
+  }
+
+  if dots.is_empty() {
+    warn!("nothing found to be imported");
+  }
+
+  apply(&imports, no)?;
+  save(dots, no)
+}
@@ -0,0 +1,130 @@
+use ::log::{error, info, warn};
+use std::{
+  fs::{read_dir, write},
+  io::{self, BufRead, Write},
+  path::Path,
+  process::exit,
+};
+use super::cfg::FILE;
+use toml::{map::Map, Value};
+
+/// Dest paths of dirs commonly found in dotfiles repos, by their names.
+const KNOWN: &[(&str, &str)] = &[
+  ("alacritty", "~/.config/alacritty"),
+  ("bash", "~"),
+  ("emacs", "~/.emacs.d"),
+  ("fish", "~/.config/fish"),
+  ("git", "~"),
+  ("i3", "~/.config/i3"),
+  ("kitty", "~/.config/kitty"),
+  ("neovim", "~/.config/nvim"),
+  ("nvim", "~/.config/nvim"),
+  ("readline", "~"),
+  ("ssh", "~/.ssh"),
+  ("sway", "~/.config/sway"),
+  ("tmux", "~"),
+  ("vim", "~"),
+  ("x11", "~"),
+  ("zsh", "~"),
+];
+
+/// Guesses where the dir `src` should be shoved into.
+fn guess(name: &str, src: &Path) -> io::Result<String> {
+  if let Some((_, dest)) = KNOWN.iter().find(|(known, _)| *known == name) {
+    return Ok(dest.to_string());
+  }
+
+  // Dirs holding dotfiles are laid out like Stow packages.
+  for entry in read_dir(src)? {
+    if entry?.file_name().to_string_lossy().starts_with('.') {
+      return Ok("~".to_owned());
+    }
+  }
+  Ok(format!("~/.config/{}", name))
+}
+
+/// Asks whether to shove the dir `name` into `dest`, returning the dest path
+/// chosen, if any.
+fn confirm(name: &str, dest: String) -> io::Result<Option<String>> {
+  print!("shove \"{}\" into \"{}\"? [Y/n/PATH] ", name, dest);
+  io::stdout().flush()?;
+
+  let mut answer = String::new();
+  if io::stdin().lock().read_line(&mut answer)? == 0 {
+    return Ok(None);
+  }
+  Ok(match answer.trim() {
+    "" | "y" | "Y" | "yes" => Some(dest),
+    "n" | "N" | "no" => None,
+    path => Some(path.to_owned()),
+  })
+}
+
+/// Exits unless the config file may be written, either because it doesn't
+/// exist yet or because it's `force`d to be overwritten.
+pub fn ensure_free(force: bool) {
+  if Path::new(FILE).exists() && !force {
+    error!("\"{}\" already exists, use \"--force\" to overwrite it", FILE);
+    exit(1);
+  }
+}
+
+/// Writes a config file with a dot for each dir in the current dir, asking
+/// for each one first if `interactive`.
+pub fn init(force: bool, interactive: bool, no: bool) {
+  ensure_free(force);
+  if let Err(err) = scaffold(interactive, no) {
+    error!("unable to init \"{}\": {}", FILE, err);
+    exit(1);
+  }
+}
+
+/// Writes the config file with `dots`, or only shows it if `no`.
+pub fn save(dots: Map<String, Value>, no: bool) -> io::Result<()> {
+  let mut cfg = Map::new();
+  cfg.insert("dots".to_owned(), Value::Table(dots));
+  let s = toml::to_string(&cfg).unwrap();
+
+  match no {
+    false => write(FILE, s),
+    true => {
+      warn!("not performing any change to the filesystem");
+      print!("{}", s);
+      Ok(())
+    }
+  }
+}
+
+fn scaffold(interactive: bool, no: bool) -> io::Result<()> {
+  let mut srcs = vec![];
+  for entry in read_dir(".")? {
+    let entry = entry?;
+    let name = entry.file_name().to_string_lossy().into_owned();
+    if entry.file_type()?.is_dir() && !name.starts_with('.') {
+      srcs.push((name, entry.path()));
+    }
+  }
+  srcs.sort();
+
+  if srcs.is_empty() {
+    warn!("no dirs found to be shoved");
+  }
+
+  let mut dots = Map::new();
+  for (name, src) in srcs {
+    let dest = guess(&name, &src)?;
+    let dest = match interactive {
+      false => Some(dest),
+      true => confirm(&name, dest)?,
+    };
+    match dest {
+      None => info!("skipping dir \"{}\"", name),
+      Some(dest) => {
+        info!("shoving \"{}\" into \"{}\"", name, dest);
+        dots.insert(name, Value::String(dest));
+      }
+    }
+  }
+
+  save(dots, no)
+}
@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use std::{
+  env::current_dir as cd,
+  fmt,
+  fs::{
+    OpenOptions,
+    Permissions,
+    create_dir_all,
+    read_to_string,
+    remove_dir,
+    remove_dir_all,
+    remove_file,
+    set_permissions,
+  },
+  io::{self, ErrorKind, Write},
+  os::unix::fs::{PermissionsExt, symlink},
+  path::{Path, PathBuf},
+};
+use super::{ft::mv, state};
+
+/// Filesystem operation performed by a run, along with whatever is needed to
+/// revert it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "op")]
+pub enum Entry {
+  Dir {path: PathBuf},
+  File {path: PathBuf},
+  Link {path: PathBuf, target: PathBuf},
+  Mode {path: PathBuf, mode: u32},
+  Moved {from: PathBuf, to: PathBuf},
+  Unlinked {path: PathBuf, target: PathBuf},
+}
+
+impl Entry {
+  /// Makes every path of the entry absolute, so that it can be reverted from
+  /// any dir. Symlink targets are kept as they are.
+  fn absolute(self) -> io::Result<Self> {
+    let cd = cd()?;
+    Ok(match self {
+      Entry::Dir {path} => Entry::Dir {path: cd.join(path)},
+      Entry::File {path} => Entry::File {path: cd.join(path)},
+      Entry::Link {path, target} => Entry::Link {path: cd.join(path), target},
+      Entry::Mode {path, mode} => Entry::Mode {path: cd.join(path), mode},
+      Entry::Moved {from, to} => {
+        Entry::Moved {from: cd.join(from), to: cd.join(to)}
+      }
+      Entry::Unlinked {path, target} => {
+        Entry::Unlinked {path: cd.join(path), target}
+      }
+    })
+  }
+
+  /// Paths which must exist, and ones which must not, for the operation to
+  /// be reverted.
+  fn needs(&self) -> (Vec<&Path>, Vec<&Path>) {
+    match self {
+      Entry::Dir {path}
+        | Entry::File {path}
+        | Entry::Link {path, ..}
+        | Entry::Mode {path, ..} => (vec![path], vec![]),
+      Entry::Moved {from, to} => (vec![to], vec![from]),
+      Entry::Unlinked {path, ..} => (vec![], vec![path]),
+    }
+  }
+
+  /// Reverts the operation.
+  pub fn undo(&self) -> io::Result<()> {
+    match self {
+      Entry::Dir {path} => remove_dir(path),
+      Entry::File {path} => remove_file(path),
+      Entry::Link {path, target} => match path.read_link()? == *target {
+        false => Err(io::Error::other("symlink was changed since created")),
+        true => remove_file(path),
+      },
+      Entry::Mode {path, mode} => {
+        set_permissions(path, Permissions::from_mode(*mode))
+      }
+      Entry::Moved {from, to} => {
+        if from.symlink_metadata().is_ok() {
+          return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "original path is occupied",
+          ));
+        }
+        mv(to, from)
+      }
+      Entry::Unlinked {path, target} => symlink(target, path),
+    }
+  }
+}
+
+impl fmt::Display for Entry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Entry::Dir {path} => write!(f, "remove dir \"{}\"", path.display()),
+      Entry::File {path} => write!(f, "remove file \"{}\"", path.display()),
+      Entry::Link {path, ..} => {
+        write!(f, "remove symlink \"{}\"", path.display())
+      }
+      Entry::Mode {path, mode} => write!(
+        f,
+        "set mode of \"{}\" back to {:o}",
+        path.display(),
+        mode,
+      ),
+      Entry::Moved {from, to} => write!(
+        f,
+        "move \"{}\" back to \"{}\"",
+        to.display(),
+        from.display(),
+      ),
+      Entry::Unlinked {path, target} => write!(
+        f,
+        "link \"{}\" to \"{}\" again",
+        path.display(),
+        target.display(),
+      ),
+    }
+  }
+}
+
+/// Where a path would be found once some entries are reverted.
+enum Spot {
+  /// Whatever is at the given path on disk now.
+  Disk(PathBuf),
+  /// Nowhere, since it's removed by reverting some entry.
+  Gone,
+  /// Somewhere, since it's created by reverting some entry.
+  Made,
+}
+
+/// Locates `p` once the `undone` entries are reverted, from the last to the
+/// first, following moved parents.
+fn locate(p: &Path, undone: &[Entry]) -> Spot {
+  let (first, rest) = match undone.split_first() {
+    None => return Spot::Disk(p.to_owned()),
+    Some(split) => split,
+  };
+  match first {
+    Entry::Dir {path} | Entry::File {path} | Entry::Link {path, ..}
+      if p.starts_with(path) => Spot::Gone,
+    Entry::Moved {from, to} => match p.strip_prefix(from) {
+      Ok(sub) if sub.as_os_str().is_empty() => locate(to, rest),
+      Ok(sub) => locate(&to.join(sub), rest),
+      Err(_) if p.starts_with(to) => Spot::Gone,
+      Err(_) => locate(p, rest),
+    },
+    Entry::Unlinked {path, ..} if p == path => Spot::Made,
+    _ => locate(p, rest),
+  }
+}
+
+/// Record of the filesystem operations performed by the last run.
+///
+/// Entries are appended to the journal file as soon as they happen, so that
+/// the journal survives runs which were interrupted midway.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Journal {
+  #[serde(default, rename = "entry")]
+  pub entries: Vec<Entry>,
+}
+
+impl Journal {
+  /// Makes sure every entry can be reverted, from the last to the first,
+  /// before any of them is.
+  pub fn check(&self) -> io::Result<()> {
+    for (i, entry) in self.entries.iter().enumerate().rev() {
+      let fail = |why: &str| {
+        io::Error::other(format!("unable to {}: {}", entry, why))
+      };
+      let (present, absent) = entry.needs();
+      for p in present.iter().chain(&absent) {
+        if p.is_relative() {
+          return Err(fail("path is relative"));
+        }
+      }
+
+      // Entries after this one are reverted by then.
+      let undone = &self.entries[i + 1..];
+      let found = |p: &Path| match locate(p, undone) {
+        Spot::Disk(p) => p.symlink_metadata().is_ok(),
+        Spot::Gone => false,
+        Spot::Made => true,
+      };
+      if present.iter().any(|p| !found(p)) {
+        return Err(fail("path no longer exists"));
+      }
+      if absent.iter().any(|p| found(p)) {
+        return Err(fail("original path is occupied"));
+      }
+      if let Entry::Link {path, target} = entry {
+        let changed = match locate(path, undone) {
+          Spot::Disk(p) => p.read_link().ok().as_ref() != Some(target),
+          Spot::Gone | Spot::Made => false,
+        };
+        if changed {
+          return Err(fail("symlink was changed since created"));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Starts a new journal, discarding the one from the previous run.
+  pub fn begin() -> io::Result<Self> {
+    Self::clear()?;
+    create_dir_all(Self::stash_dir())?;
+    Ok(Journal::default())
+  }
+
+  pub fn clear() -> io::Result<()> {
+    match remove_file(Self::path()) {
+      Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+      _ => (),
+    }
+    match remove_dir_all(Self::stash_dir()) {
+      Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+      _ => Ok(()),
+    }
+  }
+
+  pub fn load() -> io::Result<Self> {
+    match read_to_string(Self::path()) {
+      Err(err) => match err.kind() {
+        ErrorKind::NotFound => Ok(Journal::default()),
+        _ => Err(err),
+      },
+      Ok(s) => toml::from_str(&s)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err)),
+    }
+  }
+
+  pub fn path() -> PathBuf {
+    state::dir().join("journal.toml")
+  }
+
+  /// Records an operation which was just performed, with absolute paths so
+  /// that `undo` doesn't depend on the dir it's run from.
+  pub fn push(&mut self, entry: Entry) -> io::Result<()> {
+    let chunk = Journal {entries: vec![entry.absolute()?]};
+    let s = toml::to_string(&chunk)
+      .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    OpenOptions::new()
+      .append(true)
+      .create(true)
+      .open(Self::path())?
+      .write_all(s.as_bytes())?;
+    self.entries.extend(chunk.entries);
+    Ok(())
+  }
+
+  /// Path where the next file removed by the run should be moved into, so
+  /// that it can be brought back later.
+  pub fn stash(&self) -> PathBuf {
+    Self::stash_dir().join(self.entries.len().to_string())
+  }
+
+  fn stash_dir() -> PathBuf {
+    state::dir().join("journal")
+  }
+}
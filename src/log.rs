@@ -10,7 +10,13 @@ use log::{
   set_logger,
   set_max_level,
 };
-use std::{io::Write, panic::set_hook, process::exit, sync::Once};
+use std::{
+  io::Write,
+  panic::set_hook,
+  process::exit,
+  ptr::addr_of,
+  sync::{Once, atomic::{AtomicUsize, Ordering}},
+};
 use super::cli::ColorWhen;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
@@ -43,6 +49,9 @@ lazy_static! {
   static ref DEFAULT_SPEC: ColorSpec = ColorSpec::default();
 }
 
+/// Number of errors logged so far.
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
 static INIT: Once = Once::new();
 
 static mut LOGGER: Logger = Logger {
@@ -70,6 +79,7 @@ impl Log for Logger {
     }
 
     if let Level::Error = record.level() {
+      ERRORS.fetch_add(1, Ordering::Relaxed);
       let mut stderr = StandardStream::stderr(self.stderr_choice);
       stderr.set_color(&BOLD_RED_SPEC).unwrap();
       write!(stderr, "ERROR").unwrap();
@@ -109,6 +119,12 @@ impl Log for Logger {
   }
 }
 
+/// Number of errors logged so far, which didn't kill the program when in
+/// berserker mode.
+pub fn errors() -> usize {
+  ERRORS.load(Ordering::Relaxed)
+}
+
 pub fn init(verbose: usize, berserker: bool, color: ColorWhen) {
   INIT.call_once(|| {
     let stderr_choice: ColorChoice;
@@ -139,7 +155,7 @@ pub fn init(verbose: usize, berserker: bool, color: ColorWhen) {
       LOGGER.berserker = berserker;
       LOGGER.stderr_choice = stderr_choice;
       LOGGER.stdout_choice = stdout_choice;
-      set_logger(&LOGGER).unwrap();
+      set_logger(&*addr_of!(LOGGER)).unwrap();
     }
 
     set_max_level(match verbose {
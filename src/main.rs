@@ -1,35 +1,139 @@
+mod alt;
+mod backup;
 mod cfg;
 mod cli;
 mod dot;
 mod ft;
+mod host;
+mod import;
+mod init;
+mod journal;
 mod log;
+mod manifest;
+mod mode;
+mod plan;
 mod shover;
+mod state;
+mod status;
+mod template;
 
+use ::log::error;
 use clap::Parser;
-use self::{cfg::Cfg, cli::Opts, shover::Shover};
-use std::{fs::read_to_string, io::ErrorKind};
-
-const CFG_FILE: &str = ".shove.toml";
+use self::{cfg::Cfg, cli::{Cmd, Opts}, host::HOST, shover::Shover};
+use std::{env::set_current_dir as cd, process::exit};
 
 fn main() {
-  let opts = Opts::parse();
-
-  let cfg: Cfg = {
-    let s = match read_to_string(CFG_FILE) {
-      Err(err) => match err.kind() {
-        ErrorKind::NotFound => panic!("could not find \"{}\"", CFG_FILE),
-        _ => panic!("unable to read \"{}\": {}", CFG_FILE, err),
-      },
-      Ok(s) => s,
-    };
-    toml::from_str(&s).unwrap()
+  let mut opts = Opts::parse();
+
+  // There's no config file to be loaded yet.
+  match &opts.cmd {
+    Some(Cmd::Import {dir, dotfiles, force, target, tool}) => {
+      let berserker = opts.berserker.is_some_and(Into::into);
+      log::init(opts.verbose(), berserker, opts.color);
+      import::import(*tool, dir, target.as_deref(), *dotfiles, *force, opts.no);
+      return;
+    }
+    Some(Cmd::Init {force, interactive}) => {
+      let berserker = opts.berserker.is_some_and(Into::into);
+      log::init(opts.verbose(), berserker, opts.color);
+      init::init(*force, *interactive, opts.no);
+      return;
+    }
+    _ => (),
+  }
+
+  let found = cfg::find(opts.config.as_deref());
+  let cfg = match &found {
+    Err(err) => Err(format!("unable to look for config file: {}", err)),
+    Ok(None) => Err(format!(
+      "unable to find \"{}\" in the current dir or its parents, nor \"{}\"",
+      cfg::FILE,
+      cfg::user().display(),
+    )),
+    Ok(Some(path)) => Cfg::load(path),
   };
 
+  // Relative paths in the config file are relative to the dir holding it.
+  let cfg = cfg.and_then(|cfg| {
+    let path = found.as_ref().unwrap().as_ref().unwrap();
+    match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+      None => Ok(cfg),
+      Some(dir) => cd(dir).map(|_| cfg).map_err(|err| {
+        format!("unable to enter \"{}\": {}", dir.display(), err)
+      }),
+    }
+  });
+
+  // Without a chosen profile, the one named after the host is the default.
+  let (cfg, profile, picked) = match cfg {
+    Err(err) => (Err(err), None, None),
+    Ok(mut cfg) => {
+      let profile = opts.profile.clone().or_else(|| {
+        let host = Some(HOST.hostname.clone());
+        host.filter(|host| cfg.profiles.contains_key(host))
+      });
+      let picked = profile.as_ref().map(|name| cfg.profile(name));
+      (Ok(cfg), profile, picked)
+    }
+  };
+
+  // Checking reports every problem instead of dying on the first one.
   log::init(
     opts.verbose(),
-    opts.berserker.map_or_else(|| cfg.berserker, |s| s.into()),
+    matches!(opts.cmd, Some(Cmd::Check)) || opts.berserker.map_or_else(
+      || cfg.as_ref().is_ok_and(|cfg| cfg.berserker),
+      |s| s.into(),
+    ),
     opts.color,
   );
 
-  Shover::new(cfg, opts).shove_dots();
+  let cfg = match cfg {
+    // Undoing the last run doesn't need any config.
+    Err(_) if matches!((&found, &opts.cmd), (Ok(None), Some(Cmd::Undo))) => {
+      Cfg::default()
+    }
+    Err(err) => {
+      error!("{}", err);
+      exit(1);
+    }
+    Ok(cfg) => cfg,
+  };
+
+  match picked {
+    None => (),
+    Some(None) => {
+      error!("no profile named \"{}\"", profile.unwrap());
+      exit(1);
+    }
+    // Every dot is checked, no matter the profile.
+    Some(Some(_)) if matches!(opts.cmd, Some(Cmd::Check)) => (),
+    // Dots from the command line take priority over the profile ones.
+    Some(Some(picked)) => {
+      let names = match &mut opts.cmd {
+        Some(Cmd::Restore {dots}) | Some(Cmd::Status {dots}) => dots,
+        Some(Cmd::Check) | Some(Cmd::Undo) | None => &mut opts.dots,
+        Some(Cmd::Import {..}) | Some(Cmd::Init {..}) => unreachable!(),
+      };
+      if names.is_empty() && opts.tags.is_empty() {
+        *names = picked;
+      }
+    }
+  }
+
+  match opts.cmd {
+    Some(Cmd::Check) => {
+      if !Shover::new(cfg, opts).check() {
+        exit(1);
+      }
+    }
+    Some(Cmd::Import {..}) | Some(Cmd::Init {..}) => unreachable!(),
+    Some(Cmd::Restore {..}) => Shover::new(cfg, opts).restore(),
+    Some(Cmd::Status {..}) => {
+      if !Shover::new(cfg, opts).status() {
+        exit(1);
+      }
+    }
+    Some(Cmd::Undo) => Shover::new(cfg, opts).undo(),
+    None => Shover::new(cfg, opts).shove_dots(),
+  }
 }
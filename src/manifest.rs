@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fs::{create_dir_all, read_to_string, remove_file, write},
+  io::{self, ErrorKind},
+  os::unix::ffi::OsStrExt,
+  path::{Path, PathBuf},
+};
+use super::{ft::{digest, file_id}, plan::Action, state};
+
+/// Record of every dir and symlink created by Shove for a dot.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Manifest {
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub dirs: BTreeSet<PathBuf>,
+
+  /// Copied or rendered files, mapped to the hash of the content they were
+  /// written with.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub copies: BTreeMap<PathBuf, String>,
+
+  /// Hardlinks, mapped to their device and inode numbers.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub hardlinks: BTreeMap<PathBuf, (u64, u64)>,
+
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub links: BTreeMap<PathBuf, PathBuf>,
+
+  /// Files moved out of the way, mapped to where they were backed up.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub backups: BTreeMap<PathBuf, PathBuf>,
+
+  /// File the manifest is kept in.
+  #[serde(skip)]
+  path: PathBuf,
+}
+
+impl Manifest {
+  fn forget(&mut self, p: &Path) {
+    self.copies.remove(p);
+    self.dirs.remove(p);
+    self.hardlinks.remove(p);
+    self.links.remove(p);
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.backups.is_empty()
+      && self.copies.is_empty()
+      && self.dirs.is_empty()
+      && self.hardlinks.is_empty()
+      && self.links.is_empty()
+  }
+
+  /// Dir holding the manifests of the dots defined by the config file `cfg`,
+  /// so that dots of different config files never share a manifest.
+  pub fn dir(cfg: &Path) -> PathBuf {
+    let id = digest(cfg.as_os_str().as_bytes());
+    state::dir().join("manifests").join(&id[..16])
+  }
+
+  /// Loads the manifest of the dot `name` from the dir `dir`.
+  pub fn load(dir: &Path, name: &str) -> io::Result<Self> {
+    let path = dir.join(Self::file(name));
+    let manifest = match read_to_string(&path) {
+      Err(err) => match err.kind() {
+        ErrorKind::NotFound => Manifest::default(),
+        _ => return Err(err),
+      },
+      Ok(s) => toml::from_str(&s)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?,
+    };
+    Ok(Manifest {path, ..manifest})
+  }
+
+  /// Name of the file holding the manifest of the dot `name`, escaped so that
+  /// it can't point outside of its dir.
+  fn file(name: &str) -> String {
+    let name = name.replace('%', "%25").replace('/', "%2F");
+    match name.strip_prefix('.') {
+      None => format!("{}.toml", name),
+      Some(rest) => format!("%2E{}.toml", rest),
+    }
+  }
+
+  /// Updates the manifest with an action which was successfully applied.
+  pub fn record(&mut self, act: &Action) {
+    match act {
+      Action::Adopt {..} => (),
+      Action::Backup {path, to, ..} => {
+        self.backups.insert(path.clone(), to.clone());
+        self.forget(path);
+      }
+      Action::Copy {dest, hash, ..} | Action::Render {dest, hash, ..} => {
+        self.copies.insert(dest.clone(), hash.clone());
+      }
+      Action::Dir(p) | Action::Root(p) => {
+        self.dirs.insert(p.clone());
+      }
+      Action::Hardlink {dest, ..} => if let Ok(id) = file_id(dest) {
+        self.hardlinks.insert(dest.clone(), id);
+      }
+      Action::Link {dest, target} | Action::Relink {dest, target} => {
+        self.links.insert(dest.clone(), target.clone());
+      }
+      Action::Restore {path, ..} => {
+        self.backups.remove(path);
+      }
+      Action::Rm {path, ..} => self.forget(path),
+      // Links shoved before manifests existed are adopted here.
+      Action::Skip(p) => if let Ok(target) = p.read_link() {
+        self.links.insert(p.clone(), target);
+      }
+    }
+  }
+
+  pub fn save(&mut self) -> io::Result<()> {
+    // Forget about files which were removed behind our back.
+    self.backups.retain(|_, p| p.symlink_metadata().is_ok());
+    self.copies.retain(|p, _| p.is_file() && !p.is_symlink());
+    self.dirs.retain(|p| p.is_dir());
+    self.hardlinks.retain(|p, _| p.is_file() && !p.is_symlink());
+    self.links.retain(|p, _| p.is_symlink());
+
+    if self.is_empty() {
+      return match remove_file(&self.path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+      };
+    }
+    if let Some(dir) = self.path.parent() {
+      create_dir_all(dir)?;
+    }
+    let s = toml::to_string(self)
+      .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    write(&self.path, s)
+  }
+}
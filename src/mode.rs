@@ -0,0 +1,52 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::Path};
+
+/// How dotfiles are installed.
+#[derive(
+  Clone,
+  Copy,
+  Debug,
+  Default,
+  Deserialize,
+  Eq,
+  Ord,
+  PartialEq,
+  PartialOrd,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+  Copy,
+  Hardlink,
+  #[default]
+  Link,
+}
+
+/// Install modes of the dotfiles of a dot, by regex matching their paths.
+#[derive(Clone, Debug)]
+pub struct Modes {
+  default: Mode,
+  patterns: Vec<(Regex, Mode)>,
+}
+
+impl Modes {
+  /// Patterns are given from the highest priority to the lowest.
+  pub fn new<'a, I>(default: Mode, patterns: I) -> Result<Self, regex::Error>
+    where I: IntoIterator<Item = &'a BTreeMap<String, Mode>>
+  {
+    let mut v = vec![];
+    for map in patterns {
+      for (re, mode) in map {
+        v.push((Regex::new(re)?, *mode));
+      }
+    }
+    Ok(Modes {default, patterns: v})
+  }
+
+  pub fn get(&self, p: &Path) -> Mode {
+    let p = p.to_string_lossy();
+    self.patterns.iter()
+      .find(|(re, _)| re.is_match(&p))
+      .map_or(self.default, |(_, mode)| *mode)
+  }
+}
@@ -0,0 +1,342 @@
+use log::{debug, error, info, warn};
+use std::{
+  collections::HashSet,
+  fmt,
+  fs::{
+    copy,
+    create_dir,
+    create_dir_all,
+    hard_link,
+    set_permissions,
+    write,
+  },
+  io,
+  os::unix::fs::symlink,
+  path::{Path, PathBuf},
+  process::exit,
+};
+use super::{
+  ft::{Error as FtErr, Ft, RageErr, Type, mv, relink},
+  journal::{Entry, Journal},
+  manifest::Manifest,
+};
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Action {
+  Adopt {dest: PathBuf, src: PathBuf},
+  Backup {path: PathBuf, rage: usize, to: PathBuf, ty: Type},
+  Copy {dest: PathBuf, hash: String, src: PathBuf},
+  Dir(PathBuf),
+  Hardlink {dest: PathBuf, src: PathBuf},
+  Link {dest: PathBuf, target: PathBuf},
+  Relink {dest: PathBuf, target: PathBuf},
+  Render {dest: PathBuf, hash: String, src: PathBuf, text: String},
+  Restore {backup: PathBuf, path: PathBuf},
+  Rm {path: PathBuf, rage: usize, ty: Type},
+  Root(PathBuf),
+  Skip(PathBuf),
+}
+
+impl Action {
+  fn apply(&self, journal: &mut Journal) -> Result<(), FtErr> {
+    match self {
+      Action::Adopt {dest, src} => {
+        let stash = journal.stash();
+        mv(src, &stash)?;
+        journal.push(Entry::Moved {from: src.clone(), to: stash})?;
+        mv(dest, src)?;
+        journal.push(Entry::Moved {from: dest.clone(), to: src.clone()})?;
+      }
+      Action::Backup {path, rage, to, ty} => {
+        Ft {path, ty: *ty}.backup(*rage, to)?;
+        journal.push(Entry::Moved {from: path.clone(), to: to.clone()})?;
+      }
+      Action::Copy {dest, src, ..} => {
+        copy(src, dest)?;
+        journal.push(Entry::File {path: dest.clone()})?;
+      }
+      Action::Dir(p) => {
+        create_dir(p)?;
+        journal.push(Entry::Dir {path: p.clone()})?;
+      }
+      Action::Hardlink {dest, src} => {
+        hard_link(src, dest)?;
+        journal.push(Entry::File {path: dest.clone()})?;
+      }
+      Action::Link {dest, target} => {
+        symlink(target, dest)?;
+        journal.push(Entry::Link {path: dest.clone(), target: target.clone()})?;
+      }
+      Action::Relink {dest, target} => {
+        let old = dest.read_link()?;
+        relink(target, dest)?;
+        journal.push(Entry::Unlinked {path: dest.clone(), target: old})?;
+        journal.push(Entry::Link {path: dest.clone(), target: target.clone()})?;
+      }
+      Action::Render {dest, src, text, ..} => {
+        write(dest, text)?;
+        journal.push(Entry::File {path: dest.clone()})?;
+        set_permissions(dest, src.metadata()?.permissions())?;
+      }
+      Action::Restore {backup, path} => {
+        mv(backup, path)?;
+        journal.push(Entry::Moved {from: backup.clone(), to: path.clone()})?;
+      }
+      Action::Rm {path, rage, ty: Type::Dotlink} => {
+        let target = path.read_link()?;
+        Ft {path, ty: Type::Dotlink}.rm(*rage)?;
+        journal.push(Entry::Unlinked {path: path.clone(), target})?;
+      }
+      // Anything else is kept in the journal until the next run.
+      Action::Rm {path, rage, ty} => {
+        let stash = journal.stash();
+        Ft {path, ty: *ty}.backup(*rage, &stash)?;
+        journal.push(Entry::Moved {from: path.clone(), to: stash})?;
+      }
+      Action::Root(p) => {
+        let mut missing: Vec<_> = p.ancestors()
+          .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+          .map(Path::to_path_buf)
+          .collect();
+        create_dir_all(p)?;
+        while let Some(path) = missing.pop() {
+          journal.push(Entry::Dir {path})?;
+        }
+      }
+      Action::Skip(_) => (),
+    }
+    Ok(())
+  }
+
+  fn blocked(&self) -> Option<RageErr> {
+    match self {
+      Action::Backup {rage, ty, ..} | Action::Rm {rage, ty, ..}
+        if *rage < ty.rage() =>
+      {
+        Some(RageErr::new(*ty, *rage, ty.rage()))
+      }
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for Action {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Action::Adopt {dest, src} => write!(
+        f,
+        "adopt \"{}\" into \"{}\"",
+        dest.display(),
+        src.display(),
+      ),
+      Action::Backup {path, to, ty, ..} => {
+        write!(
+          f,
+          "back up {} \"{}\" into \"{}\"",
+          ty,
+          path.display(),
+          to.display(),
+        )?;
+        match ty.rage() {
+          0 => Ok(()),
+          min => write!(f, " with rage level {}", min),
+        }
+      }
+      Action::Copy {dest, src, ..} => write!(
+        f,
+        "copy \"{}\" into \"{}\"",
+        src.display(),
+        dest.display(),
+      ),
+      Action::Dir(p) => write!(f, "create dir \"{}\"", p.display()),
+      Action::Hardlink {dest, src} => write!(
+        f,
+        "hardlink \"{}\" to \"{}\"",
+        dest.display(),
+        src.display(),
+      ),
+      Action::Link {dest, target} => write!(
+        f,
+        "link \"{}\" to \"{}\"",
+        dest.display(),
+        target.display(),
+      ),
+      Action::Relink {dest, target} => write!(
+        f,
+        "relink \"{}\" to \"{}\"",
+        dest.display(),
+        target.display(),
+      ),
+      Action::Render {dest, src, ..} => write!(
+        f,
+        "render \"{}\" into \"{}\"",
+        src.display(),
+        dest.display(),
+      ),
+      Action::Restore {backup, path} => write!(
+        f,
+        "restore \"{}\" from \"{}\"",
+        path.display(),
+        backup.display(),
+      ),
+      Action::Rm {path, ty, ..} => {
+        write!(f, "remove {} \"{}\"", ty, path.display())?;
+        match ty.rage() {
+          0 => Ok(()),
+          min => write!(f, " with rage level {}", min),
+        }
+      }
+      Action::Root(p) => write!(f, "create dest root dir \"{}\"", p.display()),
+      Action::Skip(p) => write!(f, "skip \"{}\", already shoved", p.display()),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DotPlan {
+  pub acts: Vec<Action>,
+  pub manifest: Manifest,
+  pub name: String,
+}
+
+/// Actions to be performed on the filesystem, grouped by dot.
+///
+/// Besides the actions themselves, the plan keeps track of which paths will be
+/// created or removed by the actions pushed so far, so that later entries can
+/// be planned as if the earlier ones were already applied.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Plan {
+  pub dots: Vec<DotPlan>,
+  fresh: HashSet<PathBuf>,
+  gone: HashSet<PathBuf>,
+  stuck: HashSet<PathBuf>,
+}
+
+impl Plan {
+  /// Whether `p` is known to not exist once the plan is applied up to now.
+  pub fn absent(&self, p: &Path) -> bool {
+    self.gone.contains(p) || p.ancestors().skip(1).any(|p| {
+      self.fresh.contains(p) || self.gone.contains(p)
+    })
+  }
+
+  pub fn apply(&self, berserker: bool) {
+    // Don't leave a half-applied plan behind if it's doomed to fail anyway.
+    if !berserker {
+      let blocked = self.dots.iter()
+        .flat_map(|dot| &dot.acts)
+        .find_map(|act| Some((act, act.blocked()?)));
+      if let Some((act, err)) = blocked {
+        error!("refusing to {}: {}", act, err);
+        exit(1);
+      }
+    }
+
+    let idle = self.dots.iter()
+      .flat_map(|dot| &dot.acts)
+      .all(|act| matches!(act, Action::Skip(_)));
+    if idle {
+      debug!("nothing to be done, keeping the previous journal");
+    }
+
+    let mut journal = match idle {
+      false => match Journal::begin() {
+        Err(err) => {
+          error!("unable to start journal: {}", err);
+          exit(1);
+        }
+        Ok(journal) => journal,
+      },
+      true => Journal::default(),
+    };
+
+    for dot in &self.dots {
+      info!("shoving dot \"{}\"", dot.name);
+      let mut manifest = dot.manifest.clone();
+      for act in &dot.acts {
+        match act {
+          Action::Skip(_) => debug!("{}", act),
+          _ => info!("{}", act),
+        }
+        match act.apply(&mut journal) {
+          Err(err) => {
+            // Keep track of what was done so far before bailing out.
+            if !berserker {
+              save(&mut manifest, &dot.name, berserker);
+            }
+            error!("failed to {}: {}", act, err);
+            if !berserker {exit(1);}
+          }
+          Ok(_) => manifest.record(act),
+        }
+      }
+      save(&mut manifest, &dot.name, berserker);
+    }
+  }
+
+  pub fn dot(&mut self, name: &str, manifest: Manifest) {
+    self.dots.push(DotPlan {acts: vec![], manifest, name: name.to_owned()});
+  }
+
+  /// Whether every entry of the dir `p` will be removed by the plan.
+  pub fn emptied(&self, p: &Path) -> io::Result<bool> {
+    for entry in p.read_dir()? {
+      if !self.gone.contains(&entry?.path()) {
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  /// Manifest of the dot being planned.
+  pub fn manifest(&self) -> &Manifest {
+    &self.dots.last().expect("no dot being planned").manifest
+  }
+
+  pub fn push(&mut self, act: Action) {
+    match &act {
+      Action::Dir(p) | Action::Root(p) => {
+        self.gone.remove(p);
+        self.fresh.insert(p.clone());
+      }
+      Action::Backup {path, ..} | Action::Rm {path, ..} => match act.blocked() {
+        None => {self.gone.insert(path.clone());}
+        Some(_) => {self.stuck.insert(path.clone());}
+      }
+      // Entries beneath a dir shoved as a symlink are the dotfiles themselves.
+      Action::Skip(p) if p.is_symlink() => {self.stuck.insert(p.clone());}
+      _ => (),
+    }
+    self.dots.last_mut().expect("no dot being planned").acts.push(act);
+  }
+
+  /// Prints what applying the plan would do, skipped dotfiles aside.
+  pub fn show(&self) {
+    for dot in &self.dots {
+      println!("would shove dot \"{}\"", dot.name);
+      for act in &dot.acts {
+        match act.blocked() {
+          None => match act {
+            Action::Skip(_) => debug!("would {}", act),
+            _ => println!("would {}", act),
+          }
+          Some(err) => warn!("would not {}: {}", act, err),
+        }
+      }
+    }
+  }
+
+  /// Whether some ancestor of `p` could not be cleared by the plan, or is
+  /// kept as a symlink to a dotfile dir.
+  pub fn stuck(&self, p: &Path) -> bool {
+    p.ancestors().skip(1).any(|p| self.stuck.contains(p))
+  }
+}
+
+/// Saves the manifest of the dot `name`.
+fn save(manifest: &mut Manifest, name: &str, berserker: bool) {
+  if let Err(err) = manifest.save() {
+    error!("unable to save manifest of dot \"{}\": {}", name, err);
+    if !berserker {exit(1);}
+  }
+}
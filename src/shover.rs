@@ -1,31 +1,246 @@
-use log::{error, info, trace, debug, warn};
+use ::log::{debug, error, info, trace, warn};
 use pathdiff::diff_paths;
 use regex::RegexSet;
 use std::{
   borrow::Cow,
-  io::ErrorKind,
+  collections::{BTreeMap, HashMap, HashSet},
+  ffi::OsStr,
+  fs::read_to_string,
+  io::{self, ErrorKind},
+  iter::once,
   env::current_dir as cd,
-  fs::{create_dir, create_dir_all},
-  os::unix::fs::symlink,
-  path::Path,
+  mem::take,
+  ops::Bound::{Excluded, Unbounded},
+  path::{Path, PathBuf},
   process::exit,
+  rc::Rc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use super::{
+  alt,
+  backup::Backup,
+  cfg::Cfg,
+  cli::{Cmd, Opts},
+  dot::{Dot, Dots, Sh},
+  ft::{Ft, Type, hash},
+  journal::Journal,
+  log,
+  manifest::Manifest,
+  mode::{Mode, Modes},
+  plan::{Action, Plan},
+  status::State,
+  template::{self, Rendered},
 };
-use super::{cfg::Cfg, cli::Opts, dot::{Dots, Sh}, ft::{Ft, Type}};
 use walkdir::{DirEntry, WalkDir};
 
+/// Settings given from the command line, which take priority over the ones
+/// overridden by dots.
+#[derive(Clone, Copy, Debug, Default)]
+struct Forced {
+  absolute: Option<bool>,
+  depth: Option<usize>,
+  follow: Option<bool>,
+  rage: Option<usize>,
+}
+
+#[derive(Clone)]
 pub struct Shover {
   absolute: bool,
+  adopt: bool,
+  backup: Backup,
   berserker: bool,
   depth: usize,
-  dots: Dots,
+  dots: Rc<Dots>,
   follow: bool,
+  forced: Forced,
   ignore: Option<RegexSet>,
+  manifests: PathBuf,
+  mode: Mode,
+  modes: BTreeMap<String, Mode>,
   no: bool,
+  prune: bool,
   rage: usize,
+  stamp: u64,
+  template: Option<String>,
   unshove: bool,
+  vars: BTreeMap<String, String>,
 }
 
 impl Shover {
+  /// Whether `e` isn't an alternate, or is one whose conditions hold.
+  fn alternate(&self, e: &DirEntry) -> bool {
+    let conds = match e.file_name().to_str().and_then(alt::split) {
+      None => return true,
+      Some((_, conds)) => conds,
+    };
+    match alt::matches(conds) {
+      Err(err) => {
+        error!("in alternate \"{}\": {}", e.path().display(), err);
+        if !self.berserker {exit(1);}
+        false
+      }
+      Ok(false) => {
+        debug!("skipping alternate \"{}\"", e.path().display());
+        false
+      }
+      Ok(true) => true,
+    }
+  }
+
+  /// Reports every problem found with the dots without changing anything,
+  /// returning whether there's none.
+  pub fn check(&self) -> bool {
+    // Dest paths claimed by each dot, along with whether they're dirs, which
+    // several dots may share.
+    let mut claimed: BTreeMap<PathBuf, (&str, bool)> = BTreeMap::new();
+
+    // Whether each of the global ignore regexes matched anything.
+    let global = self.ignore.as_ref();
+    let mut global_used = vec![false; global.map_or(0, RegexSet::len)];
+
+    for dot in self.dots.iter().lint() {
+      let dot = match dot {
+        Err(errs) => {
+          errs.iter().for_each(|err| error!("{}", err));
+          continue;
+        }
+        Ok(dot) => dot,
+      };
+
+      let shover = match self.dotted(&dot) {
+        Err(err) => {
+          error!("{}", err);
+          continue;
+        }
+        Ok(shover) => shover,
+      };
+
+      if let Err(err) = shover.modes(&dot) {
+        error!("{}", err);
+      }
+
+      if let Some(ignore) = &shover.ignore {
+        let used = shover.ignore_used(ignore, &dot.srcs);
+        match dot.ignore {
+          None => {
+            global_used.iter_mut().zip(used).for_each(|(g, u)| *g |= u);
+          }
+          Some(_) => {
+            let unused = ignore.patterns().iter().zip(used).filter(|(_, u)| !u);
+            for (re, _) in unused {
+              error!(
+                "in dot \"{}\": ignore regex \"{}\" matches nothing",
+                dot.name,
+                re,
+              );
+            }
+          }
+        }
+      }
+
+      // Dots for other machines may take the same paths as the ones for this
+      // one.
+      if self.dots[dot.name].when().is_some_and(|when| !when.eval()) {
+        continue;
+      }
+
+      // Dots which this one clashes with, reported once each.
+      let mut clashes = HashSet::new();
+
+      let entries = shover.walk(&dot.srcs, false);
+      for root in dot.dests.iter().cloned().map(Self::root) {
+        let paths = entries.iter().map(|(src, entry)| {
+          (shover.dest(&root, src, entry).0, shover.node(entry))
+        });
+
+        for (dest, node) in once((root.to_path_buf(), true)).chain(paths) {
+          let other = Self::claim(&mut claimed, dot.name, &dest, node);
+          if let Some(other) = other.filter(|other| clashes.insert(*other)) {
+            error!(
+              "dots \"{}\" and \"{}\" would both be shoved into \"{}\"",
+              other,
+              dot.name,
+              dest.display(),
+            );
+          }
+        }
+      }
+    }
+
+    let patterns = global.map_or(&[][..], RegexSet::patterns);
+    for (re, _) in patterns.iter().zip(global_used).filter(|(_, u)| !u) {
+      error!("ignore regex \"{}\" matches nothing", re);
+    }
+
+    log::errors() == 0
+  }
+
+  /// Claims `dest` for the dot `name`, returning another dot which would
+  /// shove into the same path or beneath a file of its own, if any.
+  fn claim<'a>(
+    claimed: &mut BTreeMap<PathBuf, (&'a str, bool)>,
+    name: &'a str,
+    dest: &Path,
+    node: bool,
+  ) -> Option<&'a str> {
+    // Nodes are merged with the dirs of other dots, but other dest files
+    // take the whole path, hiding whatever would be beneath them.
+    let clash = claimed.get(dest)
+      .filter(|(_, other_node)| !node || !other_node);
+    let above = dest.ancestors()
+      .skip(1)
+      .find_map(|p| claimed.get(p).filter(|(_, node)| !node));
+    let beneath = claimed.range::<Path, _>((Excluded(dest), Unbounded))
+      .take_while(|(p, _)| p.starts_with(dest))
+      .map(|(_, claim)| claim)
+      .find(|_| !node);
+
+    let other = [clash, above, beneath].into_iter()
+      .flatten()
+      .map(|(other, _)| *other)
+      .find(|other| *other != name);
+    claimed.entry(dest.to_path_buf()).or_insert((name, node));
+    other
+  }
+
+  /// Shover with the settings overridden by `dot`, except for the ones given
+  /// from the command line.
+  fn dotted(&self, dot: &Dot) -> Result<Cow<'_, Self>, String> {
+    let mut shover = Cow::Borrowed(self);
+    if let (None, Some(absolute)) = (self.forced.absolute, dot.absolute) {
+      shover.to_mut().absolute = absolute;
+    }
+    if let (None, Some(depth)) = (self.forced.depth, dot.depth) {
+      shover.to_mut().depth = depth;
+    }
+    if let (None, Some(follow)) = (self.forced.follow, dot.follow) {
+      shover.to_mut().follow = follow;
+    }
+    if let (None, Some(rage)) = (self.forced.rage, dot.rage) {
+      shover.to_mut().rage = rage;
+    }
+    if let Some(ignore) = dot.ignore {
+      let ignore = RegexSet::new(ignore).map_err(|err| {
+        format!("in dot \"{}\": invalid ignore regexes: {}", dot.name, err)
+      })?;
+      shover.to_mut().ignore = Some(ignore);
+    }
+    Ok(shover)
+  }
+
+  /// Names of the dots in the group `name` along with the ones tagged with
+  /// it, if there's any such group or tag.
+  fn group<'c>(cfg: &'c Cfg, name: &str) -> Option<Vec<&'c String>> {
+    let grouped = cfg.groups.get(name).into_iter().flatten();
+    let tagged = cfg.dots.keys()
+      .filter(|dot| cfg.dots[*dot].tags().iter().any(|tag| tag == name));
+    let names: Vec<_> = grouped.chain(tagged).collect();
+    match names.is_empty() && !cfg.groups.contains_key(name) {
+      false => Some(names),
+      true => None,
+    }
+  }
+
   fn ignored<P>(&self, p: P) -> bool where P: AsRef<Path> {
     self.ignore.as_ref().map_or_else(
       || false,
@@ -33,30 +248,127 @@ impl Shover {
     )
   }
 
+  /// Tells which of the `ignore` regexes match any path beneath `srcs`.
+  fn ignore_used(&self, ignore: &RegexSet, srcs: &[&Path]) -> Vec<bool> {
+    let mut used = vec![false; ignore.len()];
+    for src in srcs {
+      let mut walker = WalkDir::new(src).min_depth(1).follow_links(self.follow);
+      if self.depth > 0 {
+        walker = walker.max_depth(self.depth);
+      }
+
+      for entry in walker {
+        match entry {
+          Err(err) => error!("{}", err),
+          Ok(entry) => {
+            for i in ignore.matches(&entry.path().to_string_lossy()) {
+              used[i] = true;
+            }
+          }
+        }
+      }
+    }
+    used
+  }
+
   pub fn new(mut cfg: Cfg, opts: Opts) -> Self {
-    let absolute = opts.absolute.map_or_else(|| cfg.absolute, |s| s.into());
-    let berserker = opts.berserker.map_or_else(|| cfg.berserker, |s| s.into());
-    let depth = opts.depth.unwrap_or(cfg.depth);
-    let follow = opts.follow.map_or_else(|| cfg.follow, |s| s.into());
+    let forced = Forced {
+      absolute: opts.absolute.map(Into::into),
+      depth: opts.depth,
+      follow: opts.follow.map(Into::into),
+      rage: opts.rage,
+    };
+    let absolute = forced.absolute.unwrap_or(cfg.absolute);
+    let adopt = opts.adopt;
+    let backup = cfg.backup;
+    let check = matches!(opts.cmd, Some(Cmd::Check));
+    let berserker = check
+      || opts.berserker.map_or_else(|| cfg.berserker, |s| s.into());
+    let depth = forced.depth.unwrap_or(cfg.depth);
+    let follow = forced.follow.unwrap_or(cfg.follow);
+    let manifests = Manifest::dir(&cfg.path);
+    let mode = cfg.mode;
+    let modes = take(&mut cfg.modes);
     let no = opts.no;
-    let rage = opts.rage.unwrap_or(cfg.rage);
+    let prune = opts.prune;
+    let rage = forced.rage.unwrap_or(cfg.rage);
+    let stamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let template = take(&mut cfg.template);
     let unshove = opts.unshove;
+    let vars = take(&mut cfg.vars);
 
-    let dots = match opts.dots.is_empty() {
-      false => {
-        let mut dots = Dots::default();
-        opts.dots.iter().for_each(|name| {
-          let info = match cfg.dots.remove(name) {
-            None => {error!("no dot named \"{}\"", name); return},
-            Some(info) => info,
-          };
-          dots.insert(name.to_owned(), info);
-        });
-        dots
-      }
-      true => cfg.dots,
+    let names = match &opts.cmd {
+      Some(Cmd::Restore {dots}) | Some(Cmd::Status {dots}) => dots,
+      Some(Cmd::Check)
+        | Some(Cmd::Import {..})
+        | Some(Cmd::Init {..})
+        | Some(Cmd::Undo)
+        | None => &opts.dots,
     };
 
+    // Checks go on in alphabetical order, so that every dot gets linted.
+    if let Err(err) = cfg.dots.sort() {
+      error!("{}", err);
+      if !check {exit(1);}
+    }
+
+    // Groups and tags, either given as "@NAME" or with "--tag", are expanded
+    // into the names of their dots.
+    let mut picked = vec![];
+    let groups = opts.tags.iter().map(String::as_str).chain(
+      names.iter().filter_map(|name| name.strip_prefix('@')),
+    );
+    for group in groups {
+      match Self::group(&cfg, group) {
+        None => error!("no group or tag named \"{}\"", group),
+        Some(names) => picked.extend(names),
+      }
+    }
+    picked.extend(names.iter().filter(|name| !name.starts_with('@')));
+
+    let mut skipped = HashSet::new();
+    for group in &opts.skip_tags {
+      match Self::group(&cfg, group) {
+        None => error!("no group or tag named \"{}\"", group),
+        Some(names) => skipped.extend(names.into_iter().cloned()),
+      }
+    }
+
+    // Selected dots pull in the ones they require.
+    if !names.is_empty() || !opts.tags.is_empty() {
+      let mut selected = HashSet::new();
+      let mut queue = picked;
+      while let Some(name) = queue.pop() {
+        match cfg.dots.get(name) {
+          None => error!("no dot named \"{}\"", name),
+          Some(info) => if selected.insert(name) {
+            queue.extend(info.requires());
+          }
+        }
+      }
+      let selected: HashSet<_> = selected.into_iter().cloned().collect();
+      cfg.dots.retain(|name, _| selected.contains(name));
+    }
+
+    cfg.dots.retain(|name, _| {
+      let skip = skipped.contains(name);
+      if skip {
+        info!("skipping dot \"{}\"", name);
+      }
+      !skip
+    });
+
+    let mut dots = cfg.dots;
+
+    // Dots for other machines are checked as well.
+    dots.retain(|name, info| match info.when() {
+      Some(when) if !check && !when.eval() => {
+        info!("skipping dot \"{}\", \"{}\" doesn't hold", name, when);
+        false
+      }
+      _ => true,
+    });
+
     let ignore = match RegexSet::new(&cfg.ignore) {
       Err(err) => {
         error!("invalid ignore regexes: {}", err);
@@ -68,134 +380,492 @@ impl Shover {
 
     Shover {
       absolute,
+      adopt,
+      backup,
       berserker,
       depth,
-      dots,
+      dots: Rc::new(dots),
       follow,
+      forced,
       ignore,
+      manifests,
+      mode,
+      modes,
       no,
+      prune,
       rage,
+      stamp,
+      template,
       unshove,
+      vars,
     }
   }
 
+  fn modes(&self, dot: &Dot) -> Result<Modes, String> {
+    let patterns = dot.modes.into_iter().chain(once(&self.modes));
+    Modes::new(dot.mode.unwrap_or(self.mode), patterns).map_err(|err| {
+      format!("in dot \"{}\": invalid mode regexes: {}", dot.name, err)
+    })
+  }
+
   fn node(&self, e: &DirEntry) -> bool {
     e.path().is_dir() && (self.depth == 0 || e.depth() < self.depth)
   }
 
-  fn shove<P>(&self, src: &DirEntry, dest: P) where P: AsRef<Path> {
-    let dest = dest.as_ref();
-
-    {
-      let src = src.path().display();
-      let dest = dest.display();
-      match self.unshove {
-        false => info!("shoving \"{}\" into \"{}\"", src, dest),
-        true => info!("unshoving \"{}\" from \"{}\"", src, dest),
-      }
-    }
-
-    if self.no {
-      trace!("leaving the filesystem as is");
+  fn plan_entry(
+    &self,
+    plan: &mut Plan,
+    src: &DirEntry,
+    dest: &Path,
+    mode: Mode,
+    rendered: Option<Rendered>,
+  ) {
+    if plan.stuck(dest) {
+      trace!("skipping \"{}\", its parent is in the way", dest.display());
       return;
     }
 
     let node = self.node(src);
 
-    trace!("attempting to remove old dest file");
-    match dest.symlink_metadata() {
-      Ok(_) => {
-        let ft = match Ft::new(src.path(), dest) {
-          Err(err) => {
-            error!("{}", err);
-            if !self.berserker {exit(1);}
-            return;
-          }
-          Ok(ft) => ft,
-        };
+    trace!("checking dest file \"{}\"", dest.display());
+    match plan.absent(dest) {
+      false => match dest.symlink_metadata() {
+        Ok(_) => {
+          let ft = match &rendered {
+            None if mode == Mode::Copy => {
+              let recorded = plan.manifest().copies.get(dest);
+              Self::copied_ft(src.path(), dest, recorded)
+                .map_err(|err| err.to_string())
+            }
+            None => Ft::new(src.path(), dest)
+              .map(|ft| (ft, false))
+              .map_err(|err| err.to_string()),
+            Some(rendered) => {
+              let recorded = plan.manifest().copies.get(dest);
+              Self::rendered_ft(dest, rendered, recorded).map_err(|err| {
+                format!("unable to read dest file: {}", err)
+              })
+            }
+          };
+          let (ft, fresh) = match ft {
+            Err(err) => {
+              error!("{}", err);
+              if !self.berserker {exit(1);}
+              return;
+            }
+            Ok(ft) => ft,
+          };
 
-        match ft.ty {
-          Type::Dotlink if !self.unshove => {
-            match ft.path.read_link().unwrap().is_absolute() == self.absolute {
-              false => match ft.rm(self.rage) {
-                Err(err) => {
-                  error!("unable to remove bad dotlink: {}", err);
-                  if !self.berserker {exit(1);}
+          let stale = ft.ty == Type::Symlink
+            && rendered.is_none()
+            && Self::stale(dest, plan.manifest());
+          let ty = match ft.ty {
+            Type::Symlink if stale => Type::Dotlink,
+            Type::NonemptyDir => match plan.emptied(dest) {
+              Err(err) => {
+                error!("unable to read dest dir: {}", err);
+                if !self.berserker {exit(1);}
+                return;
+              }
+              Ok(false) => Type::NonemptyDir,
+              Ok(true) if plan.manifest().dirs.contains(dest) => Type::Dotdir,
+              Ok(true) => Type::EmptyDir,
+            }
+            Type::EmptyDir if plan.manifest().dirs.contains(dest) => {
+              Type::Dotdir
+            }
+            ty => ty,
+          };
+
+          let rm = self.rm(dest, ty);
+          match ty {
+            Type::Dotcopy if !node && !self.unshove && fresh => {
+              plan.push(Action::Skip(dest.to_owned()));
+              return;
+            }
+            Type::Hardlink
+              if !node && !self.unshove && mode == Mode::Hardlink =>
+            {
+              plan.push(Action::Skip(dest.to_owned()));
+              return;
+            }
+            Type::Dotlink if !self.unshove && (node || mode == Mode::Link) => {
+              let abs = ft.path.read_link().unwrap().is_absolute();
+              match !stale && abs == self.absolute {
+                false if !node => {
+                  let target = self.target(src.path(), dest);
+                  plan.push(Action::Relink {dest: dest.to_owned(), target});
+                  return;
+                }
+                false => plan.push(rm),
+                true => {
+                  plan.push(Action::Skip(dest.to_owned()));
                   return;
                 }
-                Ok(_) => debug!("removed bad dotlink"),
-              }
-              true => {
-                debug!("dotfile already properly shoved");
-                return;
               }
             }
-          }
-          Type::EmptyDir | Type::NonemptyDir if node && !self.unshove => {
-            debug!("dotfile dir already properly shoved");
-            return;
-          }
-          _ => match ft.rm(self.rage) {
-            Err(err) => {
-              error!("unable to remove dest file: {}", err);
-              if !self.berserker {exit(1);}
+            Type::EmptyDir | Type::NonemptyDir if node && !self.unshove => {
+              plan.push(Action::Skip(dest.to_owned()));
+              return;
+            }
+            Type::File
+              if self.unshove && plan.manifest().copies.contains_key(dest) =>
+            {
+              warn!(
+                "leaving \"{}\" alone, it was changed since shoved",
+                dest.display(),
+              );
               return;
             }
-            Ok(_) => debug!("removed dest {}", ft.ty),
+            Type::File if !node && self.adopt && rendered.is_none() => {
+              plan.push(Action::Adopt {
+                dest: dest.to_owned(),
+                src: src.path().to_owned(),
+              })
+            }
+            _ => {
+              let blocked = self.rage < ty.rage();
+              plan.push(rm);
+              if blocked {return;}
+            }
           }
         }
+        Err(err) => match err.kind() {
+          ErrorKind::NotFound => trace!("dest file doesn't exist yet"),
+          _ => {
+            error!("unable to read dest file: {}", err);
+            if !self.berserker {exit(1);}
+            return;
+          }
+        }
+      }
+      true => trace!("dest file won't exist by then"),
+    }
+
+    if self.unshove {return;}
+
+    match node {
+      false => match rendered {
+        Some(Rendered {hash, text}) => plan.push(Action::Render {
+          dest: dest.to_owned(),
+          hash,
+          src: src.path().to_owned(),
+          text,
+        }),
+        None => self.plan_install(plan, src, dest, mode),
       }
-      Err(err) => match err.kind() {
-        ErrorKind::NotFound => trace!("dest file doesn't exist yet"),
+      true => plan.push(Action::Dir(dest.to_owned())),
+    }
+  }
+
+  fn plan_install(
+    &self,
+    plan: &mut Plan,
+    src: &DirEntry,
+    dest: &Path,
+    mode: Mode,
+  ) {
+    match mode {
+      Mode::Copy => match hash(src.path()) {
+        Err(err) => {
+          error!("unable to read dotfile: {}", err);
+          if !self.berserker {exit(1);}
+        }
+        Ok(hash) => plan.push(Action::Copy {
+          dest: dest.to_owned(),
+          hash,
+          src: src.path().to_owned(),
+        }),
+      }
+      Mode::Hardlink => plan.push(Action::Hardlink {
+        dest: dest.to_owned(),
+        src: src.path().to_owned(),
+      }),
+      Mode::Link => {
+        let target = self.target(src.path(), dest);
+        plan.push(Action::Link {dest: dest.to_owned(), target});
+      }
+    }
+  }
+
+  /// Plans the removal of recorded dotfiles which weren't `seen` in the
+  /// source tree.
+  fn plan_manifest(
+    &self,
+    plan: &mut Plan,
+    manifest: &Manifest,
+    seen: &HashSet<PathBuf>,
+  ) {
+    for (dest, target) in &manifest.links {
+      self.plan_recorded(plan, dest, seen, || Ft::recorded(target, dest));
+    }
+
+    for (dest, hash) in &manifest.copies {
+      self.plan_recorded(plan, dest, seen, || Ft::copied(hash, dest));
+    }
+
+    for (dest, id) in &manifest.hardlinks {
+      self.plan_recorded(plan, dest, seen, || Ft::hardlinked(*id, dest));
+    }
+
+    // Nested dirs come after their parents, so go backwards.
+    for dir in manifest.dirs.iter().rev() {
+      if seen.contains(dir) || plan.absent(dir) {continue;}
+      if dir.is_symlink() || !dir.is_dir() {continue;}
+
+      match plan.emptied(dir) {
+        Err(err) => {
+          error!("unable to read recorded dest dir: {}", err);
+          if !self.berserker {exit(1);}
+        }
+        Ok(false) => debug!("leaving non-empty dir \"{}\"", dir.display()),
+        Ok(true) => plan.push(Action::Rm {
+          path: dir.clone(),
+          rage: self.rage,
+          ty: Type::Dotdir,
+        }),
+      }
+    }
+  }
+
+  /// Plans the removal of a recorded dest file, as long as `classify` tells
+  /// it's still the dotfile left there by Shove.
+  fn plan_recorded<'a, F>(
+    &self,
+    plan: &mut Plan,
+    dest: &'a Path,
+    seen: &HashSet<PathBuf>,
+    classify: F,
+  ) where F: FnOnce() -> io::Result<Ft<'a>> {
+    if seen.contains(dest) || plan.absent(dest) {return;}
+
+    trace!("checking recorded dest file \"{}\"", dest.display());
+    if let Err(err) = dest.symlink_metadata() {
+      match err.kind() {
+        ErrorKind::NotFound => trace!("recorded dest file no longer exists"),
         _ => {
-          error!("unable to read dest file: {}", err);
+          error!("unable to read recorded dest file: {}", err);
           if !self.berserker {exit(1);}
-          return;
         }
       }
+      return;
     }
 
-    if self.unshove {return;}
+    match classify() {
+      Err(err) => {
+        error!("{}", err);
+        if !self.berserker {exit(1);}
+      }
+      Ok(ft) if ft.ty.rage() == 0 => plan.push(Action::Rm {
+        path: dest.to_owned(),
+        rage: self.rage,
+        ty: ft.ty,
+      }),
+      Ok(ft) => warn!(
+        "leaving \"{}\" alone, it was replaced by a {}",
+        dest.display(),
+        ft.ty,
+      ),
+    }
+  }
 
-    trace!("attempting to create dest file");
-    match node {
-      false => {
-        let src = src.path().canonicalize().unwrap();
+  /// Plans the removal of dangling symlinks found among the dest files of a
+  /// dot, descending only into dirs which still exist in some source tree.
+  fn plan_dangling(
+    &self,
+    plan: &mut Plan,
+    srcs: &[&Path],
+    dest: &Path,
+    seen: &HashSet<PathBuf>,
+  ) {
+    let mut walker = WalkDir::new(dest).min_depth(1);
 
-        let path = match self.absolute {
-          false => {
-            let base = match dest.is_relative() {
-              false => Cow::Borrowed(dest),
-              true => Cow::Owned(cd().unwrap().join(dest)),
-            };
-            let base = base.parent().unwrap();
-            diff_paths(&src, base).unwrap()
-          }
-          true => src,
-        };
+    if self.depth > 0 {
+      walker = walker.max_depth(self.depth);
+    }
 
-        match symlink(path, dest) {
-          Err(err) => {
-            error!("unable to create dest symlink: {}",  err);
-            if !self.berserker {exit(1);}
-          }
-          Ok(_) => debug!("created dest symlink"),
+    let walker = walker.into_iter().filter_entry(|entry| {
+      let rel = entry.path().strip_prefix(dest).unwrap();
+      srcs.iter().map(|src| src.join(rel)).any(|src| {
+        !self.ignored(&src) && (!entry.file_type().is_dir() || src.is_dir())
+      })
+    });
+
+    for entry in walker {
+      let entry = match entry {
+        Err(err) => {
+          error!("{}", err);
+          if !self.berserker {exit(1);}
+          continue;
         }
+        Ok(entry) => entry,
+      };
+
+      let path = entry.path();
+      if !entry.path_is_symlink() || seen.contains(path) || plan.absent(path) {
+        continue;
       }
-      true => match create_dir(dest) {
+
+      match Ft::dangling(srcs, path) {
         Err(err) => {
-          error!("unable to create dest dir: {}", err);
+          error!("{}", err);
           if !self.berserker {exit(1);}
         }
-        Ok(_) => debug!("created dest dir"),
+        Ok(None) => (),
+        Ok(Some(ft)) if self.rage < ft.ty.rage() => debug!(
+          "leaving dangling {} \"{}\" alone",
+          ft.ty,
+          path.display(),
+        ),
+        Ok(Some(ft)) => plan.push(self.rm(path, ft.ty)),
       }
     }
   }
 
-  pub fn shove_dots(&self) {
-    if self.no {
-      warn!("not performing any change to the filesystem");
+  /// Plans putting back the files backed up for each dot, removing whatever
+  /// Shove left in their place.
+  fn plan_restore(&self) -> Plan {
+    let mut plan = Plan::default();
+
+    for name in self.dots.keys() {
+      let manifest = match Manifest::load(&self.manifests, name) {
+        Err(err) => {
+          error!("unable to load manifest of dot \"{}\": {}", name, err);
+          if !self.berserker {exit(1);}
+          continue;
+        }
+        Ok(manifest) => manifest,
+      };
+      plan.dot(name, manifest.clone());
+
+      for (path, backup) in &manifest.backups {
+        if backup.symlink_metadata().is_err() {
+          warn!("backup \"{}\" no longer exists", backup.display());
+          continue;
+        }
+
+        if path.symlink_metadata().is_ok() {
+          // Without a recorded target, a symlink can't be told apart from any
+          // other.
+          let target = manifest.links.get(path).cloned().unwrap_or_default();
+          let ty = match Ft::recorded(&target, path) {
+            Err(err) => {
+              error!("{}", err);
+              if !self.berserker {exit(1);}
+              continue;
+            }
+            Ok(Ft {ty: Type::Dotlink, ..}) => Type::Dotlink,
+            Ok(Ft {ty: Type::EmptyDir, ..}) if manifest.dirs.contains(path) => {
+              Type::Dotdir
+            }
+            Ok(ft) => {
+              error!(
+                "unable to restore \"{}\", it's occupied by a {}",
+                path.display(),
+                ft.ty,
+              );
+              if !self.berserker {exit(1);}
+              continue;
+            }
+          };
+          plan.push(Action::Rm {path: path.clone(), rage: self.rage, ty});
+        }
+
+        plan.push(Action::Restore {backup: backup.clone(), path: path.clone()});
+      }
+    }
+
+    plan
+  }
+
+  /// Dest path of `e`, with the conditions of alternates and the suffix of
+  /// templates removed, along with whether `e` is a template.
+  fn dest(&self, root: &Path, src: &Path, e: &DirEntry) -> (PathBuf, bool) {
+    let mut dest = root.to_path_buf();
+    let rel = e.path().strip_prefix(src).unwrap();
+    rel.iter().for_each(|name| dest.push(alt::base(name)));
+
+    let name = alt::base(e.file_name());
+    let template = self.template(e, name);
+    if template {
+      let name = name.to_str().unwrap();
+      let suffix = self.template.as_deref().unwrap();
+      dest.set_file_name(&name[..name.len() - suffix.len()]);
     }
+    (dest, template)
+  }
+
+  /// Classifies the dest file of a copied dotfile, telling whether it holds
+  /// the content of `src`. Copies recorded in the manifest are deemed
+  /// dotfiles too, even once `src` has changed.
+  fn copied_ft<'a>(
+    src: &Path,
+    dest: &'a Path,
+    recorded: Option<&String>,
+  ) -> io::Result<(Ft<'a>, bool)> {
+    let ft = Ft::new(src, dest)?;
+    match (ft.ty, recorded) {
+      (Type::Dotcopy, _) => Ok((ft, true)),
+      (Type::File, Some(hash)) => Ok((Ft::copied(hash, dest)?, false)),
+      _ => Ok((ft, false)),
+    }
+  }
+
+  fn render(&self, src: &Path) -> Result<Rendered, String> {
+    let s = read_to_string(src).map_err(|err| {
+      format!("unable to read template \"{}\": {}", src.display(), err)
+    })?;
+    template::render(&s, &self.vars).map(Rendered::new).map_err(|err| {
+      format!("in template \"{}\": {}", src.display(), err)
+    })
+  }
+
+  /// Classifies the dest file of a template, telling whether it holds the
+  /// `rendered` output. Outputs of previous renders are deemed dotfiles too.
+  fn rendered_ft<'a>(
+    dest: &'a Path,
+    rendered: &Rendered,
+    recorded: Option<&String>,
+  ) -> io::Result<(Ft<'a>, bool)> {
+    let ft = Ft::copied(&rendered.hash, dest)?;
+    match (ft.ty, recorded) {
+      (Type::Dotcopy, _) => Ok((ft, true)),
+      (Type::File, Some(hash)) => Ok((Ft::copied(hash, dest)?, false)),
+      _ => Ok((ft, false)),
+    }
+  }
+
+  pub fn restore(&self) {
+    self.run(self.plan_restore());
+  }
+
+  /// Removes a file, backing it up first unless it's a dotfile.
+  fn rm(&self, path: &Path, ty: Type) -> Action {
+    let path = path.to_owned();
+    let rage = self.rage;
+    match ty {
+      Type::Dotcopy | Type::Dotdir | Type::Dotlink | Type::Hardlink => {
+        Action::Rm {path, rage, ty}
+      }
+      _ => match self.backup.path(&path, self.stamp) {
+        None => Action::Rm {path, rage, ty},
+        Some(to) => Action::Backup {path, rage, to, ty},
+      }
+    }
+  }
+
+  fn root(dest: Sh) -> Cow<Path> {
+    match dest {
+      Sh::Expanded {buf, s} => {
+        trace!("expanded \"{}\" to \"{}\"", s, buf.display());
+        Cow::Owned(buf)
+      }
+      Sh::Normal(dest) => Cow::Borrowed(dest),
+    }
+  }
+
+  fn plan(&self) -> Plan {
+    let mut plan = Plan::default();
 
     for dot in self.dots.iter() {
       let dot = match dot {
@@ -207,65 +877,424 @@ impl Shover {
         Ok(dot) => dot,
       };
 
-      info!("shoving dot \"{}\"", dot.name);
+      trace!("planning dot \"{}\"", dot.name);
+      let shover = match self.dotted(&dot) {
+        Err(err) => {
+          error!("{}", err);
+          if !self.berserker {exit(1);}
+          continue;
+        }
+        Ok(shover) => shover,
+      };
+
+      let manifest = match Manifest::load(&shover.manifests, dot.name) {
+        Err(err) => {
+          error!("unable to load manifest of dot \"{}\": {}", dot.name, err);
+          if !shover.berserker {exit(1);}
+          continue;
+        }
+        Ok(manifest) => manifest,
+      };
+      plan.dot(dot.name, manifest.clone());
 
-      let src = dot.src;
-      let dest = match dot.dest {
-        Sh::Expanded {buf, s} => {
-          trace!("expanded \"{}\" to \"{}\"", s, buf.display());
-          Cow::Owned(buf)
+      let modes = match shover.modes(&dot) {
+        Err(err) => {
+          error!("{}", err);
+          if !shover.berserker {exit(1);}
+          continue;
+        }
+        Ok(modes) => modes,
+      };
+
+      let entries = shover.walk(&dot.srcs, shover.unshove);
+      let roots: Vec<_> = dot.dests.into_iter().map(Self::root).collect();
+
+      // Dest files of every root, so that none of them is deemed gone.
+      let mut seen = HashSet::new();
+
+      for root in &roots {
+        if shover.prune {
+          seen.insert(root.to_path_buf());
+        }
+
+        if !root.exists() {
+          trace!("root dest dir \"{}\" doesn't exist yet", root.display());
+          if !shover.unshove && !shover.prune {
+            plan.push(Action::Root(root.to_path_buf()));
+          }
+        }
+
+        for (src, entry) in &entries {
+          let (dest, template) = shover.dest(root, src, entry);
+          if !shover.prune {
+            let rendered = match template {
+              false => None,
+              true => match shover.render(entry.path()) {
+                Err(err) => {
+                  error!("{}", err);
+                  if !shover.berserker {exit(1);}
+                  continue;
+                }
+                Ok(rendered) => Some(rendered),
+              },
+            };
+            let mode = modes.get(entry.path());
+            shover.plan_entry(&mut plan, entry, &dest, mode, rendered);
+          }
+          seen.insert(dest);
         }
-        Sh::Normal(dest) => Cow::Borrowed(dest),
+      }
+
+      if shover.unshove || shover.prune {
+        shover.plan_manifest(&mut plan, &manifest, &seen);
+      }
+
+      for root in roots.iter().filter(|root| shover.prune && root.is_dir()) {
+        shover.plan_dangling(&mut plan, &dot.srcs, root, &seen);
+      }
+    }
+
+    plan
+  }
+
+  /// Whether `dest` is a symlink left by a previous run which no longer
+  /// points to its dotfile, such as one from another layer.
+  fn stale(dest: &Path, manifest: &Manifest) -> bool {
+    manifest.links.get(dest).is_some_and(|target| {
+      dest.read_link().is_ok_and(|link| link == *target)
+    })
+  }
+
+  fn state(
+    &self,
+    src: &DirEntry,
+    dest: &Path,
+    mode: Mode,
+    rendered: Option<&Rendered>,
+    manifest: &Manifest,
+  ) -> Result<State, String> {
+    if let Err(err) = dest.symlink_metadata() {
+      return match err.kind() {
+        ErrorKind::NotFound => Ok(State::Missing),
+        _ => Err(format!("unable to read dest file: {}", err)),
       };
+    }
+
+    if let Some(rendered) = rendered {
+      let recorded = manifest.copies.get(dest);
+      let (ft, fresh) = Self::rendered_ft(dest, rendered, recorded)
+        .map_err(|err| format!("unable to read dest file: {}", err))?;
+      return Ok(match ft.ty {
+        Type::Dotcopy if fresh => State::Shoved,
+        Type::Dotcopy => State::Outdated,
+        Type::EmptyDir | Type::NonemptyDir => State::ConflictingDir,
+        Type::File => State::ConflictingFile,
+        _ => State::ForeignSymlink,
+      });
+    }
+
+    let (ft, fresh) = match mode {
+      Mode::Copy => {
+        let recorded = manifest.copies.get(dest);
+        Self::copied_ft(src.path(), dest, recorded)
+      }
+      _ => Ft::new(src.path(), dest).map(|ft| (ft, false)),
+    }.map_err(|err| err.to_string())?;
+    let node = self.node(src);
+    Ok(match ft.ty {
+      Type::Dotcopy => match mode {
+        Mode::Copy if fresh => State::Shoved,
+        Mode::Copy => State::Outdated,
+        _ => State::BadStyle,
+      }
+      Type::Dotlink if !node && mode != Mode::Link => State::BadStyle,
+      Type::Dotlink => {
+        match dest.read_link().unwrap().is_absolute() == self.absolute {
+          false => State::BadStyle,
+          true => State::Shoved,
+        }
+      }
+      Type::Dotdir | Type::EmptyDir | Type::NonemptyDir => match node {
+        false => State::ConflictingDir,
+        true => State::Shoved,
+      }
+      Type::File => State::ConflictingFile,
+      Type::Hardlink => match mode {
+        Mode::Hardlink => State::Shoved,
+        _ => State::BadStyle,
+      }
+      Type::Symlink if Self::stale(dest, manifest) => State::BadStyle,
+      Type::Symlink => State::ForeignSymlink,
+    })
+  }
 
-      if !dest.exists() {
-        trace!("root dest dir doesn't exist yet");
-        if !self.unshove {
-          match create_dir_all(&dest) {
+  /// Reports the state of every dotfile, returning whether all of them are
+  /// properly shoved.
+  pub fn status(&self) -> bool {
+    let mut synced = true;
+
+    for dot in self.dots.iter() {
+      let dot = match dot {
+        Err(err) => {
+          error!("{}", err);
+          if !self.berserker {exit(1);}
+          synced = false;
+          continue;
+        }
+        Ok(dot) => dot,
+      };
+
+      let shover = match self.dotted(&dot) {
+        Err(err) => {
+          error!("{}", err);
+          if !self.berserker {exit(1);}
+          synced = false;
+          continue;
+        }
+        Ok(shover) => shover,
+      };
+
+      let modes = match shover.modes(&dot) {
+        Err(err) => {
+          error!("{}", err);
+          if !shover.berserker {exit(1);}
+          synced = false;
+          continue;
+        }
+        Ok(modes) => modes,
+      };
+
+      let manifest = match Manifest::load(&shover.manifests, dot.name) {
+        Err(err) => {
+          error!("unable to load manifest of dot \"{}\": {}", dot.name, err);
+          if !shover.berserker {exit(1);}
+          synced = false;
+          continue;
+        }
+        Ok(manifest) => manifest,
+      };
+
+      let entries = shover.walk(&dot.srcs, false);
+
+      for root in dot.dests.into_iter().map(Self::root) {
+        // Dest files which aren't real dirs hide whatever would be beneath
+        // them.
+        let mut hidden: Option<PathBuf> = None;
+
+        for (src, entry) in &entries {
+          let (dest, template) = shover.dest(&root, src, entry);
+          if hidden.as_ref().is_some_and(|p| dest.starts_with(p)) {
+            continue;
+          }
+
+          let rendered = match template {
+            false => None,
+            true => match shover.render(entry.path()) {
+              Err(err) => {
+                error!("{}", err);
+                if !shover.berserker {exit(1);}
+                synced = false;
+                continue;
+              }
+              Ok(rendered) => Some(rendered),
+            },
+          };
+          let mode = modes.get(entry.path());
+          let rendered = rendered.as_ref();
+          let state = match shover.state(entry, &dest, mode, rendered, &manifest)
+          {
             Err(err) => {
-              error!("unable to create dest root dir: {}", err);
-              if !self.berserker {exit(1);}
+              error!("{}", err);
+              if !shover.berserker {exit(1);}
+              synced = false;
               continue;
             }
-            Ok(_) => debug!("created dest root dir"),
+            Ok(state) => state,
+          };
+
+          let real = dest.is_dir() && !dest.is_symlink();
+          if shover.node(entry) && state != State::Missing && !real {
+            hidden = Some(dest.clone());
+          }
+
+          synced &= state.synced();
+          match dot.srcs.len() {
+            1 => println!("{}: {}: {}", dot.name, state, dest.display()),
+            _ => println!(
+              "{}: {}: {} (from \"{}\")",
+              dot.name,
+              state,
+              dest.display(),
+              src.display(),
+            ),
           }
         }
       }
+    }
 
-      let mut walker = WalkDir::new(src)
-        .min_depth(1)
-        .follow_links(self.follow);
+    synced
+  }
 
-      if self.depth > 0 {
-        walker = walker.max_depth(self.depth);
+  /// Path which a symlink at `dest` should point to in order to link `src`.
+  fn target(&self, src: &Path, dest: &Path) -> PathBuf {
+    let src = src.canonicalize().unwrap();
+    match self.absolute {
+      false => {
+        let base = match dest.is_relative() {
+          false => Cow::Borrowed(dest),
+          true => Cow::Owned(cd().unwrap().join(dest)),
+        };
+        let base = base.parent().unwrap();
+        diff_paths(&src, base).unwrap()
       }
+      true => src,
+    }
+  }
 
-      if self.unshove {
-        walker = walker.contents_first(true);
+  /// Whether `e` is a template, given the name it has without the
+  /// conditions of an alternate.
+  fn template(&self, e: &DirEntry, name: &OsStr) -> bool {
+    let suffix = match self.template.as_deref() {
+      None | Some("") => return false,
+      Some(suffix) => suffix,
+    };
+    !self.node(e) && e.path().is_file() && name.to_str().is_some_and(|name| {
+      name.len() > suffix.len() && name.ends_with(suffix)
+    })
+  }
+
+  /// Reverts the filesystem operations performed by the last run.
+  pub fn undo(&self) {
+    let journal = match Journal::load() {
+      Err(err) => {
+        error!("unable to load journal: {}", err);
+        exit(1);
       }
+      Ok(journal) => journal,
+    };
+
+    if journal.entries.is_empty() {
+      warn!("nothing to undo");
+      return;
+    }
 
-      // Filter ignored entries.
-      let walker = walker.into_iter().filter_entry(|entry| {
-        let path = entry.path();
-        let ignored = self.ignored(path);
-        if ignored {
-          warn!("ignoring path \"{}\"", path.display());
+    if let Err(err) = journal.check() {
+      error!("refusing to undo the last run: {}", err);
+      if !self.berserker {exit(1);}
+    }
+
+    if self.no {
+      warn!("not performing any change to the filesystem");
+      journal.entries.iter().rev().for_each(|entry| info!("would {}", entry));
+      return;
+    }
+
+    let mut failed = false;
+    for entry in journal.entries.iter().rev() {
+      info!("{}", entry);
+      if let Err(err) = entry.undo() {
+        error!("failed to {}: {}", entry, err);
+        if !self.berserker {exit(1);}
+        failed = true;
+      }
+    }
+
+    if failed {
+      warn!("keeping the journal since some operations couldn't be undone");
+      return;
+    }
+
+    if let Err(err) = Journal::clear() {
+      error!("unable to clear journal: {}", err);
+      if !self.berserker {exit(1);}
+    }
+  }
+
+  /// Walks every layer of a dot, where entries of later layers take the place
+  /// of the ones of earlier layers with the same dest path. Dirs found in
+  /// several layers are merged, unless they're installed as symlinks.
+  fn walk<'s>(&self, srcs: &[&'s Path], contents_first: bool)
+    -> Vec<(&'s Path, DirEntry)>
+  {
+    let mut merged: BTreeMap<PathBuf, (&Path, DirEntry)> = BTreeMap::new();
+
+    for &src in srcs {
+      for entry in self.walk_layer(src) {
+        let (rel, _) = self.dest(Path::new(""), src, &entry);
+        let replaced = merged.get(&rel)
+          .is_some_and(|(_, old)| !self.node(old) || !self.node(&entry));
+        if replaced {
+          merged.retain(|p, _| p == &rel || !p.starts_with(&rel));
         }
-        !ignored
-      });
+        merged.insert(rel, (src, entry));
+      }
+    }
 
-      for entry in walker {
-        let entry = match entry {
-          Err(err) => {
-            error!("{}", err);
+    // Parents sort before their children.
+    let entries = merged.into_values();
+    match contents_first {
+      false => entries.collect(),
+      true => entries.rev().collect(),
+    }
+  }
+
+  fn walk_layer(&self, src: &Path) -> Vec<DirEntry> {
+    let mut walker = WalkDir::new(src)
+      .min_depth(1)
+      .follow_links(self.follow);
+
+    if self.depth > 0 {
+      walker = walker.max_depth(self.depth);
+    }
+
+    // Filter ignored entries and alternates for other machines.
+    let walker = walker.into_iter().filter_entry(|entry| {
+      let path = entry.path();
+      let ignored = self.ignored(path);
+      if ignored {
+        warn!("ignoring path \"{}\"", path.display());
+      }
+      !ignored && self.alternate(entry)
+    });
+
+    // Entries whose dest files would clash, keyed by their dest paths.
+    let mut dests = HashMap::new();
+
+    walker.filter_map(|entry| match entry {
+      Err(err) => {
+        error!("{}", err);
+        if !self.berserker {exit(1);}
+        None
+      }
+      Ok(entry) => {
+        let (dest, _) = self.dest(Path::new(""), src, &entry);
+        match dests.insert(dest, entry.path().to_owned()) {
+          None => Some(entry),
+          Some(other) => {
+            error!(
+              "\"{}\" and \"{}\" would both be shoved into the same path",
+              other.display(),
+              entry.path().display(),
+            );
             if !self.berserker {exit(1);}
-            continue;
+            None
           }
-          Ok(entry) => entry,
-        };
-        let dest = dest.join(entry.path().strip_prefix(src).unwrap());
-        self.shove(&entry, &dest);
+        }
+      }
+    }).collect()
+  }
+
+  fn run(&self, plan: Plan) {
+    match self.no {
+      false => plan.apply(self.berserker),
+      true => {
+        warn!("not performing any change to the filesystem");
+        plan.show();
       }
     }
   }
+
+  pub fn shove_dots(&self) {
+    self.run(self.plan());
+  }
 }
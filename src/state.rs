@@ -0,0 +1,10 @@
+use std::{env::var_os, path::PathBuf};
+
+/// Directory where Shove keeps track of what it did to the filesystem.
+pub fn dir() -> PathBuf {
+  let base = match var_os("XDG_STATE_HOME") {
+    Some(p) if !p.is_empty() => PathBuf::from(p),
+    _ => PathBuf::from(shellexpand::tilde("~/.local/state").as_ref()),
+  };
+  base.join("shove")
+}
@@ -0,0 +1,34 @@
+use std::fmt;
+
+/// Install state of a dotfile.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum State {
+  BadStyle,
+  ConflictingDir,
+  ConflictingFile,
+  ForeignSymlink,
+  Missing,
+  Outdated,
+  Shoved,
+}
+
+impl State {
+  pub fn synced(&self) -> bool {
+    *self == State::Shoved
+  }
+}
+
+impl fmt::Display for State {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let s = match self {
+      State::BadStyle => "bad style",
+      State::ConflictingDir => "conflicting dir",
+      State::ConflictingFile => "conflicting file",
+      State::ForeignSymlink => "foreign symlink",
+      State::Missing => "missing",
+      State::Outdated => "outdated",
+      State::Shoved => "shoved",
+    };
+    write!(f, "{}", s)
+  }
+}
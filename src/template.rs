@@ -0,0 +1,71 @@
+use std::{collections::BTreeMap, env::var, error, fmt};
+use super::{ft::digest, host::HOST};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+  Unclosed(usize),
+  Undefined(usize, String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Unclosed(line) => write!(f, "unclosed \"{{{{\" at line {}", line),
+      Error::Undefined(line, name) => {
+        write!(f, "undefined variable \"{}\" at line {}", name, line)
+      }
+    }
+  }
+}
+
+impl error::Error for Error {}
+
+/// Output of a template, along with its hash.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rendered {
+  pub hash: String,
+  pub text: String,
+}
+
+impl Rendered {
+  pub fn new(text: String) -> Self {
+    Rendered {hash: digest(text.as_bytes()), text}
+  }
+}
+
+/// Looks up a template variable, giving priority to the user defined `vars`
+/// over the facts about the machine and the environment.
+fn lookup(name: &str, vars: &BTreeMap<String, String>) -> Option<String> {
+  if let Some(val) = vars.get(name) {
+    return Some(val.clone());
+  }
+  match name {
+    "arch" => Some(HOST.arch.to_owned()),
+    "hostname" => Some(HOST.hostname.clone()),
+    "os" => Some(HOST.os.to_owned()),
+    "user" => Some(HOST.user.clone()),
+    _ => var(name.strip_prefix("env.")?).ok(),
+  }
+}
+
+/// Replaces every `{{ name }}` in `s` with the value of the variable `name`.
+pub fn render(s: &str, vars: &BTreeMap<String, String>)
+  -> Result<String, Error>
+{
+  let mut out = String::with_capacity(s.len());
+  let mut rest = s;
+  while let Some(start) = rest.find("{{") {
+    let line = s[..s.len() - rest.len() + start].matches('\n').count() + 1;
+    out.push_str(&rest[..start]);
+    rest = &rest[start + 2..];
+    let end = rest.find("}}").ok_or(Error::Unclosed(line))?;
+    let name = rest[..end].trim();
+    match lookup(name, vars) {
+      None => return Err(Error::Undefined(line, name.to_owned())),
+      Some(val) => out.push_str(&val),
+    }
+    rest = &rest[end + 2..];
+  }
+  out.push_str(rest);
+  Ok(out)
+}